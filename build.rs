@@ -1,30 +1,61 @@
+use std::env;
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
 use std::f32::consts::PI;
 
-#[path = "src/sine_lookup/mod.rs"] mod sine_lookup;
-use sine_lookup::SAMPLE_POINTS;
-use sine_lookup::SCALING_FACTOR;
+#[path = "src/sine_lookup/config.rs"]
+#[allow(dead_code)]
+mod config;
+use config::FINE_SAMPLE_POINTS;
+use config::SAMPLE_POINTS;
+use config::SCALING_FACTOR;
 
 fn main() -> std::io::Result<()> {
+    println!("cargo:rustc-check-cfg=cfg(cal_hyst)");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/sine_lookup/config.rs");
+
+    // Only a quarter of the sine is stored, the rest is mirrored.
+    let quarter_wave = env::var_os("CARGO_FEATURE_QUARTER_WAVE").is_some();
+
     // Open the file and write content.
-    let out_path = Path::new("src/sine_lookup/lookup_table.rs");
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let out_path = Path::new(&out_dir).join("lookup_table.rs");
     let mut lookup_file = File::create(out_path).expect("Unable to create file for lookup table generation");
 
-    write!(lookup_file, "pub static SIN_LOOKUP_TABLE: [i32; {}] = [\n", SAMPLE_POINTS)?;
-    for point in 0..SAMPLE_POINTS {
-        let value = point as f32 / SAMPLE_POINTS as f32;
+    write_table(&mut lookup_file, "SIN_LOOKUP_TABLE", SAMPLE_POINTS, SCALING_FACTOR, quarter_wave)?;
+    writeln!(lookup_file)?;
+    write_table(&mut lookup_file, "FINE_SIN_LOOKUP_TABLE", FINE_SAMPLE_POINTS, SCALING_FACTOR, quarter_wave)?;
+
+    Ok(())
+}
+
+/// One sine period over `sample_points`, or its first quarter including the
+/// peak.
+// `is_multiple_of` needs a newer toolchain than the firmware builds with.
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn write_table(lookup_file: &mut File, name: &str, sample_points: u32, scaling: u32, quarter_wave: bool) -> std::io::Result<()> {
+    let stored_points = if quarter_wave {
+        assert!(sample_points % 4 == 0, "A quarter wave table needs a multiple of 4 sample points");
+        sample_points / 4 + 1
+    } else {
+        sample_points
+    };
+
+    writeln!(lookup_file, "pub static {}: [i32; {}] = [", name, stored_points)?;
+    for point in 0..stored_points {
+        let value = point as f32 / sample_points as f32;
         let value = (value* 2.0 * PI).sin();
-        let value = (value * SCALING_FACTOR as f32) as i32;
+        let value = (value * scaling as f32) as i32;
         write!(lookup_file, "{}",value)?;
 
-        if point != SAMPLE_POINTS-1 {
+        if point != stored_points-1 {
             write!(lookup_file, ",")?;
         }
 
         if point != 0 && point % 20 == 0 {
-            write!(lookup_file, "\n")?;
+            writeln!(lookup_file)?;
         }
     }
     write!(lookup_file, "];")?;
@@ -0,0 +1,201 @@
+use crate::position_control::{Direction, PositionInput};
+
+/// Errors reported by an absolute encoder read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncoderError {
+    /// The parity bit of the frame did not match.
+    Parity,
+    /// The magnetic field is too weak or too strong.
+    Magnet,
+    /// The encoder did not answer or flagged a framing error.
+    Communication,
+}
+
+/// An absolute (single turn) encoder, like the AS5047 or MT6816.
+pub trait AbsoluteEncoder {
+    /// Counts per rotation, 16384 for a 14 bit encoder.
+    fn resolution(&self) -> u32;
+    /// The raw angle in counts, from 0 to `resolution` - 1.
+    fn read_angle(&mut self) -> Result<u32, EncoderError>;
+}
+
+/// Adapts an `AbsoluteEncoder` to a `PositionInput`, tracking the number of
+/// turns across the wraps of the raw angle.
+pub struct AbsolutePositionInput<E: AbsoluteEncoder> {
+    encoder: E,
+    last_angle: Option<u32>,
+    turns: i32,
+    direction: Direction,
+    last_error: Option<EncoderError>,
+    error_count: u32,
+}
+
+impl<E: AbsoluteEncoder> AbsolutePositionInput<E> {
+    pub fn new(encoder: E) -> Self {
+        Self {
+            encoder,
+            last_angle: None,
+            turns: 0,
+            direction: Direction::Unknown(0),
+            last_error: None,
+            error_count: 0,
+        }
+    }
+    pub fn encoder(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+    pub fn turns(&self) -> i32 {
+        self.turns
+    }
+    /// Error of the last read, `None` if it succeeded.
+    pub fn last_error(&self) -> Option<EncoderError> {
+        self.last_error
+    }
+    /// Number of failed reads since creation.
+    pub fn error_count(&self) -> u32 {
+        self.error_count
+    }
+}
+
+impl<E: AbsoluteEncoder> PositionInput for AbsolutePositionInput<E> {
+    fn update(&mut self) {
+        let angle = match self.encoder.read_angle() {
+            Ok(angle) => angle,
+            Err(error) => {
+                // Keep the last position.
+                self.last_error = Some(error);
+                self.error_count = self.error_count.wrapping_add(1);
+                return;
+            }
+        };
+        self.last_error = None;
+
+        if let Some(last_angle) = self.last_angle {
+            let resolution = self.encoder.resolution() as i32;
+            let mut change = angle as i32 - last_angle as i32;
+
+            // Moved over the zero point?
+            if change > resolution / 2 {
+                change -= resolution;
+                self.turns = self.turns.wrapping_sub(1);
+            } else if change < -resolution / 2 {
+                change += resolution;
+                self.turns = self.turns.wrapping_add(1);
+            }
+
+            self.direction = match change {
+                0 => Direction::Unknown(0),
+                c if c > 0 => Direction::Increased(c),
+                c => Direction::Decreased(-c),
+            };
+        }
+        self.last_angle = Some(angle);
+    }
+    fn reset(&mut self) {
+        // The zero is absolute, only forget the turns.
+        self.turns = 0;
+        self.direction = Direction::Unknown(0);
+    }
+    /// Wraps like a counter after about 131k turns at 16384 counts.
+    fn get_position(&self) -> i32 {
+        self.turns
+            .wrapping_mul(self.encoder.resolution() as i32)
+            .wrapping_add(self.last_angle.unwrap_or(0) as i32)
+    }
+    fn get_direction(&self) -> Direction {
+        self.direction
+    }
+    fn is_absolute(&self) -> bool {
+        true
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockEncoder {
+        angle: Result<u32, EncoderError>,
+    }
+
+    impl AbsoluteEncoder for MockEncoder {
+        fn resolution(&self) -> u32 {
+            16384
+        }
+        fn read_angle(&mut self) -> Result<u32, EncoderError> {
+            self.angle
+        }
+    }
+
+    fn input_at(angle: u32) -> AbsolutePositionInput<MockEncoder> {
+        let mut input = AbsolutePositionInput::new(MockEncoder { angle: Ok(angle) });
+        input.update();
+        input
+    }
+
+    #[test]
+    fn position_wraps_after_many_turns() {
+        let mut input = input_at(16000);
+        input.turns = i32::MAX / 16384;
+        let before = input.get_position();
+        input.encoder().angle = Ok(100);
+        input.update();
+
+        // The change over the wrap is still the 484 counts moved.
+        assert_eq!(484, input.get_position().wrapping_sub(before));
+    }
+
+    #[test]
+    fn absolute_start_position() {
+        let input = input_at(1000);
+        assert_eq!(1000, input.get_position());
+        assert!(input.is_absolute());
+    }
+
+    #[test]
+    fn track_turns_forward() {
+        let mut input = input_at(16000);
+        input.encoder().angle = Ok(100);
+        input.update();
+
+        assert_eq!(1, input.turns());
+        assert_eq!(16384 + 100, input.get_position());
+        assert!(matches!(input.get_direction(), Direction::Increased(484)));
+    }
+
+    #[test]
+    fn track_turns_backward() {
+        let mut input = input_at(100);
+        input.encoder().angle = Ok(16000);
+        input.update();
+
+        assert_eq!(-1, input.turns());
+        assert_eq!(-384, input.get_position());
+        assert!(matches!(input.get_direction(), Direction::Decreased(484)));
+    }
+
+    #[test]
+    fn read_error_keeps_position() {
+        let mut input = input_at(500);
+        input.encoder().angle = Err(EncoderError::Parity);
+        input.update();
+
+        assert_eq!(500, input.get_position());
+        assert_eq!(Some(EncoderError::Parity), input.last_error());
+        assert_eq!(1, input.error_count());
+    }
+
+    #[test]
+    fn reset_keeps_absolute_zero() {
+        let mut input = input_at(16000);
+        input.encoder().angle = Ok(100);
+        input.update();
+        input.reset();
+
+        assert_eq!(100, input.get_position());
+    }
+}
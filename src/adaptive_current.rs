@@ -0,0 +1,87 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveConfig {
+    /// Current without load, in mA.
+    pub min_current: i32,
+    /// Current at full load, in mA.
+    pub max_current: i32,
+    /// The current drops by 1/`decay` of the difference per update when the
+    /// load goes down, it rises immediately.
+    pub decay: i32,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            min_current: 200,
+            max_current: 1000,
+            decay: 64,
+        }
+    }
+}
+
+/// Scales the coil current with the load, between a minimum and maximum.
+pub struct AdaptiveCurrent {
+    config: AdaptiveConfig,
+    current: i32,
+}
+
+impl AdaptiveCurrent {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            config,
+            current: config.max_current,
+        }
+    }
+    pub fn config(&self) -> &AdaptiveConfig {
+        &self.config
+    }
+    pub fn current(&self) -> i32 {
+        self.current
+    }
+
+    /// `load` in percent, see `PositionControl::load`.
+    pub fn update(&mut self, load: i32) -> i32 {
+        let min = self.config.min_current;
+        let max = self.config.max_current.max(min);
+        let target = min + (max - min) * load.clamp(0, 100) / 100;
+
+        self.current = if target >= self.current {
+            target
+        } else {
+            let step = ((self.current - target) / self.config.decay.max(1)).max(1);
+            self.current - step
+        };
+        self.current
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_load() {
+        let mut adaptive = AdaptiveCurrent::new(AdaptiveConfig {
+            min_current: 100,
+            max_current: 500,
+            decay: 4,
+        });
+        assert_eq!(500, adaptive.current());
+
+        // Slowly down.
+        assert_eq!(400, adaptive.update(0));
+        assert_eq!(325, adaptive.update(0));
+        for _ in 0..100 {
+            adaptive.update(0);
+        }
+        assert_eq!(100, adaptive.current());
+
+        // Up right away.
+        assert_eq!(300, adaptive.update(50));
+        assert_eq!(500, adaptive.update(150));
+    }
+}
@@ -0,0 +1,35 @@
+//! Replay a recording of a drive, see `stepper_servo_lib::replay`, and
+//! compare the outputs to the recorded ones.
+//!
+//! `cargo run --features sim --bin sim_replay -- <recording>`
+
+use std::env;
+use std::fs;
+use std::process;
+
+use stepper_servo_lib::sim::replay::replay;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: {} <recording>", args[0]);
+        process::exit(2);
+    }
+
+    let data = fs::read(&args[1]).unwrap_or_else(|error| {
+        eprintln!("{}: {}", args[1], error);
+        process::exit(2);
+    });
+    match replay(&data) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.mismatches > 0 {
+                process::exit(1);
+            }
+        }
+        Err(error) => {
+            eprintln!("{}: {}", args[1], error);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Run a scenario against the simulated motor, see `Scenario` for the
+//! format. Optionally logs every current loop period to a CSV file.
+//!
+//! `cargo run --features sim --bin sim_scenario -- <scenario> [log.csv]`
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::process;
+
+use stepper_servo_lib::sim::scenario::{Record, Scenario};
+use stepper_servo_lib::sim::{SimConfig, Simulation};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("usage: {} <scenario> [log.csv]", args[0]);
+        process::exit(2);
+    }
+
+    let text = fs::read_to_string(&args[1]).unwrap_or_else(|error| {
+        eprintln!("{}: {}", args[1], error);
+        process::exit(2);
+    });
+    let scenario = Scenario::parse(&text).unwrap_or_else(|error| {
+        eprintln!("{}: {}", args[1], error);
+        process::exit(2);
+    });
+
+    let mut log = args.get(2).map(|path| {
+        let file = File::create(path).unwrap_or_else(|error| {
+            eprintln!("{}: {}", path, error);
+            process::exit(2);
+        });
+        let mut log = BufWriter::new(file);
+        writeln!(log, "{}", Record::CSV_HEADER).expect("write log");
+        log
+    });
+
+    let mut sim = Simulation::new(SimConfig::default());
+    let report = sim.run_scenario(&scenario, |record| {
+        if let Some(log) = &mut log {
+            writeln!(log, "{}", record).expect("write log");
+        }
+    });
+    if let Some(log) = &mut log {
+        log.flush().expect("write log");
+    }
+
+    for (time_ms, response) in &report.responses {
+        println!("{:>8} ms: {}", time_ms, response);
+    }
+    for result in &report.results {
+        println!("{}", result);
+    }
+    if !report.passed() {
+        process::exit(1);
+    }
+}
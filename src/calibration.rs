@@ -1,18 +1,61 @@
+use crate::config::{EncoderConfig, MotorConfig, STEPS_PER_ELECTRICAL_CYCLE};
 use crate::position_control::PositionInput;
+use crate::sine_lookup::angle::ElectricalAngle;
+use crate::sine_lookup::lookup;
+use core::borrow::BorrowMut;
 
-const PULSES_PER_ROTATION: usize = 600 * 4;
-const ROTOR_TEETH: usize = 50;
-const ROTOR_POLES: usize = 2;
-const STEPS_PER_POLE: usize = 2; // Bipolar.
-const STEPS_PER_ROTATION: usize = ROTOR_TEETH * ROTOR_POLES * STEPS_PER_POLE;
+/// Default number of entries in the calibration table, one per encoder
+/// pulse of a 600 line encoder.
+pub const DEFAULT_TABLE_SIZE: usize = 600 * 4;
 
-pub struct DebugCalibrationData {
-    pub pulse_at_angle: [i32; PULSES_PER_ROTATION],
+/// Calibrated angles are stored as electrical degrees in fixed-point,
+/// with `ANGLE_FRACTION_BITS` bits below the whole degree.
+pub const ANGLE_FRACTION_BITS: u32 = 8;
+pub const ANGLE_SCALE: i32 = 1 << ANGLE_FRACTION_BITS;
+const FULL_CIRCLE: i32 = 360 * ANGLE_SCALE;
+const HALF_CIRCLE: i32 = FULL_CIRCLE / 2;
+
+/// Marks a table entry for which no angle was recorded.
+pub const NOT_VISITED: i32 = i32::MIN;
+const MAX_SMOOTHING_WINDOW: usize = 31;
+
+/// Filter applied to the calibration table after the gaps are filled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationFilter {
+    None,
+    /// Centered moving average over `window` encoder positions (odd, max 31).
+    MovingAverage {
+        window: usize,
+    },
+}
+
+impl Default for CalibrationFilter {
+    fn default() -> Self {
+        CalibrationFilter::MovingAverage { window: 5 }
+    }
+}
+
+/// The calibration table has `N` entries spread evenly over one rotation,
+/// independent of the encoder resolution.
+pub struct DebugCalibrationData<const N: usize> {
+    pub pulse_at_angle: [i32; N],
+}
+
+impl<const N: usize> DebugCalibrationData<N> {
+    /// Const, to be placed in a `static`, see `PositionTables`.
+    pub const fn new() -> Self {
+        Self {
+            pulse_at_angle: [NOT_VISITED; N],
+        }
+    }
+}
+
+impl<const N: usize> Default for DebugCalibrationData<N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-static mut DEBUG_CALIBRATION_DATA: DebugCalibrationData = DebugCalibrationData {
-    pulse_at_angle: [0; PULSES_PER_ROTATION],
-};
 enum CalibrationPhase {
     Step1Backwards,
     Step2Forwards,
@@ -21,44 +64,138 @@ enum CalibrationPhase {
     #[cfg(cal_hyst)]
     Step5CalibratingBackward,
 }
-pub struct Calibration {
+/// `D` is the storage of the table, see `TableStorage`.
+pub struct Calibration<
+    const N: usize = DEFAULT_TABLE_SIZE,
+    D = &'static mut DebugCalibrationData<N>,
+> {
+    motor_config: MotorConfig,
+    encoder_config: EncoderConfig,
+    data: D,
     slow_iteration: u32,
     angle_setpoint: i32,
     current_step: u32,
     current_phase: CalibrationPhase,
+    overlap_steps: u32,
     calibrated: bool,
-    //position_data: [i32; PULSES_PER_ROTATION],
+    filter: CalibrationFilter,
 }
 
-impl Default for Calibration {
-    fn default() -> Self {
+impl<const N: usize, D> Calibration<N, D>
+where
+    D: BorrowMut<DebugCalibrationData<N>>,
+{
+    /// `data` is cleared, see `PositionTables` for the storage.
+    pub fn new(motor_config: MotorConfig, encoder_config: EncoderConfig, mut data: D) -> Self {
+        data.borrow_mut()
+            .pulse_at_angle
+            .iter_mut()
+            .for_each(|angle| *angle = NOT_VISITED);
         Self {
+            motor_config,
+            encoder_config,
+            data,
             slow_iteration: 0,
             angle_setpoint: 359,
             current_step: 0,
             current_phase: CalibrationPhase::Step1Backwards,
+            overlap_steps: 0,
             calibrated: false,
-            //position_data: [0; PULSES_PER_ROTATION],
+            filter: CalibrationFilter::default(),
         }
     }
-}
-
-impl Calibration {
     pub fn reset(&mut self) {
-        *self = Self::default();
+        self.slow_iteration = 0;
+        self.angle_setpoint = 359;
+        self.current_step = 0;
+        self.current_phase = CalibrationPhase::Step1Backwards;
+        self.overlap_steps = 0;
+        self.calibrated = false;
+        self.data
+            .borrow_mut()
+            .pulse_at_angle
+            .iter_mut()
+            .for_each(|angle| *angle = NOT_VISITED);
+    }
+    pub fn motor_config(&self) -> &MotorConfig {
+        &self.motor_config
+    }
+    pub fn encoder_config(&self) -> &EncoderConfig {
+        &self.encoder_config
     }
+    pub fn set_filter(&mut self, filter: CalibrationFilter) {
+        self.filter = filter;
+    }
+
+    /// Table entry for a position within one rotation.
+    pub(crate) fn table_index(&self, position: usize) -> usize {
+        let pulses = self.encoder_config.pulses_per_rotation() as usize;
+        if pulses == N {
+            position
+        } else {
+            (position as u64 * N as u64 / pulses as u64) as usize % N
+        }
+    }
+
+    /// Electrical angle at the position, rounded to whole degrees.
     pub fn angle_at_position(&self, position: usize) -> i32 {
-        unsafe { DEBUG_CALIBRATION_DATA.pulse_at_angle[position] }
+        let angle = self.fine_angle_at_position(position);
+        ((angle + ANGLE_SCALE / 2) >> ANGLE_FRACTION_BITS) % 360
+    }
+
+    /// Electrical angle at the position in fixed-point, see `ANGLE_SCALE`.
+    pub fn fine_angle_at_position(&self, position: usize) -> i32 {
+        match self.data.borrow().pulse_at_angle[self.table_index(position)] {
+            NOT_VISITED => 0,
+            angle => angle,
+        }
     }
 
     pub fn update_position(&mut self, position: usize, angle: i32) {
-        unsafe {
-            DEBUG_CALIBRATION_DATA.pulse_at_angle[position as usize] = angle;
+        let index = self.table_index(position);
+        self.data.borrow_mut().pulse_at_angle[index] = angle.rem_euclid(360) * ANGLE_SCALE;
+    }
+
+    /// Fill the positions which were not visited and filter the table.
+    fn post_process(&mut self) {
+        let table = &mut self.data.borrow_mut().pulse_at_angle;
+        let cycles = self.motor_config.electrical_cycles_per_rotation() as i32;
+        let cycles = cycles * detect_direction(table);
+        fill_gaps(table, cycles);
+        if let CalibrationFilter::MovingAverage { window } = self.filter {
+            smooth(table, window / 2, cycles);
+        }
+    }
+
+    /// Fourth harmonic of the rotor angle error over the commanded angle, as
+    /// sine and cosine amplitude in fixed point. It comes from the 3rd and
+    /// 5th harmonic in the torque of the motor, see `Waveform`.
+    pub fn harmonic_error(&self) -> Option<(i32, i32)> {
+        if !self.calibrated {
+            return None;
+        }
+        let table = &self.data.borrow().pulse_at_angle;
+        let cycles = self.motor_config.electrical_cycles_per_rotation() as i32;
+        let cycles = cycles * detect_direction(table);
+
+        let error = |index: usize| {
+            wrap_half(table[index] - table[0] - expected_delta(index as i32, cycles, N))
+        };
+        let offset = (0..N).map(|index| error(index) as i64).sum::<i64>() / N as i64;
+
+        let (mut sine, mut cosine) = (0_i64, 0_i64);
+        for (index, angle) in table.iter().enumerate() {
+            let angle = ElectricalAngle::from_fixed_point(*angle, ANGLE_FRACTION_BITS);
+            let harmonic = ElectricalAngle(angle.0.wrapping_mul(4));
+            let (sin, cos) = lookup::sin_cos(harmonic, error(index) - offset as i32);
+            sine += sin as i64;
+            cosine += cos as i64;
         }
+        Some(((sine * 2 / N as i64) as i32, (cosine * 2 / N as i64) as i32))
     }
 
-    pub fn get_calibration_data(&self) -> &DebugCalibrationData {
-        unsafe { &DEBUG_CALIBRATION_DATA }
+    pub fn get_calibration_data(&self) -> &DebugCalibrationData<N> {
+        self.data.borrow()
     }
 
     pub fn is_calibrated(&self) -> bool {
@@ -97,6 +234,13 @@ impl Calibration {
 
             match self.current_phase {
                 // Expect angle = 359
+                CalibrationPhase::Step1Backwards if position_input.is_absolute() => {
+                    // The zero is known, no need to find it. Do one extra
+                    // electrical cycle so the first (unsettled) positions
+                    // are recorded again.
+                    self.current_phase = CalibrationPhase::Step3CalibratingForward;
+                    self.overlap_steps = STEPS_PER_ELECTRICAL_CYCLE;
+                }
                 CalibrationPhase::Step1Backwards => {
                     self.rotate_backwards();
                     if self.angle_setpoint == 0 {
@@ -120,13 +264,16 @@ impl Calibration {
                     }
 
                     // Are we done?
-                    if self.current_step == STEPS_PER_ROTATION as u32 {
+                    if self.current_step
+                        == self.motor_config.steps_per_rotation() + self.overlap_steps
+                    {
                         self.current_phase = CalibrationPhase::Step4Wait;
                     }
                 }
                 #[cfg(not(cal_hyst))]
                 CalibrationPhase::Step4Wait => {
                     // No additional step, complete
+                    self.post_process();
                     self.calibrated = true;
                 }
                 #[cfg(cal_hyst)]
@@ -152,3 +299,223 @@ impl Calibration {
         }
     }
 }
+
+/// Wrap a fixed-point angle into [0, 360).
+fn wrap_full(angle: i32) -> i32 {
+    angle.rem_euclid(FULL_CIRCLE)
+}
+
+/// Wrap a fixed-point angle difference into [-180, 180).
+fn wrap_half(angle: i32) -> i32 {
+    (angle + HALF_CIRCLE).rem_euclid(FULL_CIRCLE) - HALF_CIRCLE
+}
+
+/// Expected change of the electrical angle over a number of positions,
+/// `cycles` is the (signed) number of electrical cycles in the table.
+fn expected_delta(positions: i32, cycles: i32, table_size: usize) -> i32 {
+    (positions as i64 * cycles as i64 * FULL_CIRCLE as i64 / table_size as i64) as i32
+}
+
+/// Returns 1 if the angle increases with the position, -1 if it decreases.
+fn detect_direction(table: &[i32]) -> i32 {
+    let votes: i32 = table
+        .windows(2)
+        .filter(|pair| pair[0] != NOT_VISITED && pair[1] != NOT_VISITED)
+        .map(|pair| wrap_half(pair[1] - pair[0]).signum())
+        .sum();
+    if votes >= 0 {
+        1
+    } else {
+        -1
+    }
+}
+
+/// Linear interpolation over the positions that were not visited, the
+/// table is treated as circular so gaps over the end wrap around.
+fn fill_gaps(table: &mut [i32], cycles: i32) {
+    let size = table.len();
+    let first = match table.iter().position(|angle| *angle != NOT_VISITED) {
+        Some(first) => first,
+        None => return,
+    };
+
+    let mut previous = first;
+    for offset in 1..=size {
+        let index = (first + offset) % size;
+        if table[index] == NOT_VISITED {
+            continue;
+        }
+
+        let gap = offset - (previous + size - first) % size;
+        if gap > 1 {
+            // Use the expected slope to resolve the wrap-around of the angle.
+            let expected = expected_delta(gap as i32, cycles, size);
+            let delta = expected + wrap_half(table[index] - table[previous] - expected);
+            for step in 1..gap {
+                let angle = table[previous] + (delta as i64 * step as i64 / gap as i64) as i32;
+                table[(previous + step) % size] = wrap_full(angle);
+            }
+        }
+        previous = index;
+    }
+}
+
+/// Centered moving average over `2 * half_window + 1` positions, done in
+/// place. The expected slope is removed before averaging.
+fn smooth(table: &mut [i32], half_window: usize, cycles: i32) {
+    let size = table.len();
+    let half_window = half_window
+        .min(MAX_SMOOTHING_WINDOW / 2)
+        .min(size.saturating_sub(1) / 2);
+    if half_window == 0 {
+        return;
+    }
+
+    // Originals which get overwritten before they are used as neighbour.
+    let mut head = [0; MAX_SMOOTHING_WINDOW / 2];
+    head[..half_window].copy_from_slice(&table[..half_window]);
+    let mut processed = [0; MAX_SMOOTHING_WINDOW / 2];
+
+    for index in 0..size {
+        let center = table[index];
+        let mut sum: i64 = 0;
+        for distance in 1..=half_window {
+            let before_index = (index + size - distance) % size;
+            let before = if distance <= index {
+                processed[before_index % processed.len()]
+            } else {
+                table[before_index]
+            };
+            let after_index = index + distance;
+            let after = if after_index < size {
+                table[after_index]
+            } else {
+                head[after_index - size]
+            };
+
+            let expected = expected_delta(distance as i32, cycles, size);
+            sum += wrap_half(before + expected - center) as i64;
+            sum += wrap_half(after - expected - center) as i64;
+        }
+        processed[index % processed.len()] = center;
+        table[index] = wrap_full(center + (sum / (2 * half_window as i64 + 1)) as i32);
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 48;
+    const CYCLES: i32 = 2;
+
+    fn table<const N: usize>() -> Box<DebugCalibrationData<N>> {
+        Box::new(DebugCalibrationData::new())
+    }
+
+    fn linear_table() -> [i32; SIZE] {
+        let mut table = [0; SIZE];
+        for (position, angle) in table.iter_mut().enumerate() {
+            *angle = wrap_full(expected_delta(position as i32, CYCLES, SIZE));
+        }
+        table
+    }
+
+    #[test]
+    fn fill_gaps_interpolates() {
+        let expected = linear_table();
+        let mut table = expected;
+        table[3..8]
+            .iter_mut()
+            .for_each(|angle| *angle = NOT_VISITED);
+
+        fill_gaps(&mut table, CYCLES);
+        assert_eq!(expected, table);
+    }
+
+    #[test]
+    fn fill_gaps_wraps_around() {
+        let expected = linear_table();
+        let mut table = expected;
+        table[..4].iter_mut().for_each(|angle| *angle = NOT_VISITED);
+        table[SIZE - 5..]
+            .iter_mut()
+            .for_each(|angle| *angle = NOT_VISITED);
+
+        fill_gaps(&mut table, CYCLES);
+        assert_eq!(expected, table);
+    }
+
+    #[test]
+    fn fill_gaps_decreasing_angle() {
+        let mut expected = linear_table();
+        expected.reverse();
+        let mut table = expected;
+        table[10..30]
+            .iter_mut()
+            .for_each(|angle| *angle = NOT_VISITED);
+
+        assert_eq!(-1, detect_direction(&table));
+        fill_gaps(&mut table, -CYCLES);
+        assert_eq!(expected, table);
+    }
+
+    #[test]
+    fn smooth_removes_noise() {
+        let expected = linear_table();
+        let mut table = expected;
+        table[0] = wrap_full(table[0] + 5 * ANGLE_SCALE);
+        table[20] = wrap_full(table[20] - 5 * ANGLE_SCALE);
+
+        smooth(&mut table, 2, CYCLES);
+        for (expected, angle) in expected.iter().zip(table.iter()) {
+            assert!(wrap_half(expected - angle).abs() <= ANGLE_SCALE);
+        }
+
+        // A clean table is left untouched.
+        let mut table = expected;
+        smooth(&mut table, 2, CYCLES);
+        assert_eq!(expected, table);
+    }
+
+    #[test]
+    fn table_scaled_to_encoder() {
+        let encoder = EncoderConfig { lines: 4096 };
+        let mut calibration = Calibration::<SIZE, _>::new(MotorConfig::default(), encoder, table());
+        let pulses = encoder.pulses_per_rotation() as usize;
+
+        calibration.update_position(pulses / 2, 90);
+        assert_eq!(90, calibration.angle_at_position(pulses / 2));
+        assert_eq!(90, calibration.angle_at_position(pulses / 2 + 1));
+        assert_eq!(0, calibration.angle_at_position(0));
+    }
+
+    #[test]
+    fn harmonic_error() {
+        use std::f64::consts::PI;
+        let mut calibration = Calibration::<DEFAULT_TABLE_SIZE, _>::new(
+            MotorConfig::default(),
+            EncoderConfig::default(),
+            table(),
+        );
+        assert_eq!(None, calibration.harmonic_error());
+
+        // The rotor lags 3 degrees behind at 22.5 degrees (and each 90).
+        let cycles = MotorConfig::default().electrical_cycles_per_rotation() as i32;
+        for (position, angle) in calibration.data.pulse_at_angle.iter_mut().enumerate() {
+            let rotor = 1000 + expected_delta(position as i32, cycles, DEFAULT_TABLE_SIZE);
+            let radians = rotor as f64 / FULL_CIRCLE as f64 * 2.0 * PI;
+            let error = 3.0 * ANGLE_SCALE as f64 * (4.0 * radians).sin();
+            *angle = wrap_full(rotor + error as i32);
+        }
+        calibration.calibrated = true;
+
+        let (sine, cosine) = calibration.harmonic_error().unwrap();
+        assert!((sine - 3 * ANGLE_SCALE).abs() < ANGLE_SCALE / 8, "{}", sine);
+        assert!(cosine.abs() < ANGLE_SCALE / 8, "{}", cosine);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::calibration::DEFAULT_TABLE_SIZE;
+use core::borrow::BorrowMut;
+
+const NOT_VISITED: i16 = i16::MIN;
+/// Samples per table entry are averaged with this IIR filter.
+const SAMPLE_FILTER: i32 = 4;
+
+/// Detent torque compensation, per rotor position within one rotation. The
+/// entries are the holding current in mA, signed with the direction of the
+/// torque, needed to keep the position. Uses the same positions as the
+/// `Calibration` table.
+pub struct CoggingTable<const N: usize = DEFAULT_TABLE_SIZE, C = &'static mut [i16; N]> {
+    currents: C,
+    measuring: bool,
+    valid: bool,
+    enabled: bool,
+    /// Compensate only below this velocity, in pulses per second.
+    max_velocity: i32,
+}
+
+impl<const N: usize, C> CoggingTable<N, C>
+where
+    C: BorrowMut<[i16; N]>,
+{
+    /// `currents` are cleared, see `PositionTables` for the storage.
+    pub fn new(mut currents: C) -> Self {
+        currents
+            .borrow_mut()
+            .iter_mut()
+            .for_each(|current| *current = 0);
+        Self {
+            currents,
+            measuring: false,
+            valid: false,
+            enabled: true,
+            max_velocity: 1200,
+        }
+    }
+    pub fn is_measuring(&self) -> bool {
+        self.measuring
+    }
+    /// A measurement was completed.
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+    pub fn set_max_velocity(&mut self, max_velocity: i32) {
+        self.max_velocity = max_velocity;
+    }
+    pub fn currents(&self) -> &[i16; N] {
+        self.currents.borrow()
+    }
+
+    pub fn start_measurement(&mut self) {
+        self.currents
+            .borrow_mut()
+            .iter_mut()
+            .for_each(|current| *current = NOT_VISITED);
+        self.measuring = true;
+        self.valid = false;
+    }
+
+    /// Stop measuring, the table is not valid afterwards.
+    pub fn cancel_measurement(&mut self) {
+        if self.measuring {
+            self.measuring = false;
+            self.invalidate();
+        }
+    }
+    /// The table does not match the calibration anymore.
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /// Holding current at the table `index`, in mA.
+    pub fn add_sample(&mut self, index: usize, current: i32) {
+        let current = current.clamp(i16::MIN as i32 + 1, i16::MAX as i32);
+        let entry = &mut self.currents.borrow_mut()[index % N];
+        *entry = if *entry == NOT_VISITED {
+            current as i16
+        } else {
+            let average = *entry as i32;
+            (average + (current - average) / SAMPLE_FILTER) as i16
+        };
+    }
+
+    /// Remove the average, which is friction and load instead of cogging.
+    pub fn finish_measurement(&mut self) {
+        let (sum, count) = self
+            .currents
+            .borrow()
+            .iter()
+            .filter(|current| **current != NOT_VISITED)
+            .fold((0_i64, 0_i64), |(sum, count), current| {
+                (sum + *current as i64, count + 1)
+            });
+        let average = if count > 0 { sum / count } else { 0 };
+
+        for current in self.currents.borrow_mut().iter_mut() {
+            *current = if *current == NOT_VISITED {
+                0
+            } else {
+                (*current as i64 - average) as i16
+            };
+        }
+        self.measuring = false;
+        self.valid = count > 0;
+    }
+
+    /// Feed forward current at the table `index`, in mA.
+    pub fn compensation(&self, index: usize, velocity: i32) -> i32 {
+        if !self.valid || !self.enabled || self.measuring || velocity.abs() > self.max_velocity {
+            0
+        } else {
+            self.currents.borrow()[index % N] as i32
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measure_and_compensate() {
+        let mut table = CoggingTable::<8, _>::new([0; 8]);
+        assert_eq!(0, table.compensation(0, 0));
+
+        table.start_measurement();
+        for (index, current) in [100, 300, 100, -200].iter().enumerate() {
+            table.add_sample(index, *current);
+        }
+        // Filtered.
+        table.add_sample(1, 700);
+        table.finish_measurement();
+        assert!(table.is_valid());
+
+        // Average of 100 removed, not visited is 0.
+        assert_eq!(&[0, 300, 0, -300, 0, 0, 0, 0], table.currents());
+        assert_eq!(300, table.compensation(1, 1200));
+        assert_eq!(300, table.compensation(9, -1200));
+
+        // Not at speed.
+        assert_eq!(0, table.compensation(1, 1201));
+        table.set_enabled(false);
+        assert_eq!(0, table.compensation(1, 0));
+    }
+}
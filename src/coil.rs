@@ -1,9 +1,10 @@
 use crate::current_control::CurrentDevice;
-use crate::sine_lookup::lookup;
+use crate::sine_lookup::angle::ElectricalAngle;
+use crate::waveform::Waveform;
 
 pub struct Coil<T: CurrentDevice> {
     current_output: T,
-    angle_setpoint: i32,
+    angle_setpoint: ElectricalAngle,
     current_setpoint: i32,
 }
 
@@ -11,14 +12,20 @@ impl<T: CurrentDevice> Coil<T> {
     pub fn new(current_output: T) -> Self {
         Self {
             current_output,
-            angle_setpoint: 0,
+            angle_setpoint: ElectricalAngle::ZERO,
             current_setpoint: 0,
         }
     }
-    pub fn set_angle(&mut self, degrees: i32, current: i32) {
-        self.angle_setpoint = degrees;
+    pub fn set_angle(&mut self, angle: ElectricalAngle, current: i32, waveform: &Waveform) {
+        self.angle_setpoint = angle;
         self.current_setpoint = current;
-        let current = lookup::get_sine(self.angle_setpoint as u32, self.current_setpoint);
+        let current = waveform.value(self.angle_setpoint, self.current_setpoint);
+        self.current_output.set_current(current);
+    }
+    /// Add `current` at `angle` to the last `set_angle`, for a feed forward.
+    pub fn add_current(&mut self, angle: ElectricalAngle, current: i32, waveform: &Waveform) {
+        let current = waveform.value(self.angle_setpoint, self.current_setpoint)
+            + waveform.value(angle, current);
         self.current_output.set_current(current);
     }
     pub fn set_current(&mut self, current: i32) {
@@ -40,6 +47,7 @@ impl<T: CurrentDevice> Coil<T> {
 mod tests {
     use super::*;
 
+    #[allow(dead_code)]
     struct MockCurrentOutput {
         pub current: i32,
     }
@@ -62,6 +70,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn feed_forward_current() {
+        let mut coil = Coil::new(MockCurrentOutput { current: 0 });
+        let waveform = Waveform::Sine;
+        coil.set_angle(ElectricalAngle::ZERO, 1000, &waveform);
+        assert_eq!(0, coil.current_control().current());
+
+        coil.add_current(ElectricalAngle::QUARTER, 200, &waveform);
+        assert_eq!(200, coil.current_control().current());
+        coil.add_current(ElectricalAngle::QUARTER, -200, &waveform);
+        assert_eq!(-200, coil.current_control().current());
+    }
+
     // #[test]
     // fn motor_pos_test() {
     //     let mut coil =
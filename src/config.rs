@@ -0,0 +1,86 @@
+const ROTOR_POLES: u32 = 2;
+const STEPS_PER_POLE: u32 = 2; // Bipolar.
+pub(crate) const STEPS_PER_ELECTRICAL_CYCLE: u32 = 4; // Step at: 0, 90, 180, 270
+const QUADRATURE_EDGES: u32 = 4;
+
+/// Geometry of the stepper motor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotorConfig {
+    /// 50 for a 1.8 degree motor, 100 for a 0.9 degree motor.
+    pub rotor_teeth: u32,
+}
+
+impl MotorConfig {
+    pub const STEP_1_8_DEGREE: Self = Self { rotor_teeth: 50 };
+    pub const STEP_0_9_DEGREE: Self = Self { rotor_teeth: 100 };
+
+    pub fn steps_per_rotation(&self) -> u32 {
+        self.rotor_teeth * ROTOR_POLES * STEPS_PER_POLE
+    }
+
+    pub fn electrical_cycles_per_rotation(&self) -> u32 {
+        self.steps_per_rotation() / STEPS_PER_ELECTRICAL_CYCLE
+    }
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self::STEP_1_8_DEGREE
+    }
+}
+
+/// Resolution of the (quadrature) encoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncoderConfig {
+    /// Lines per rotation, each line gives 4 pulses.
+    pub lines: u32,
+}
+
+impl EncoderConfig {
+    /// Absolute encoders report counts instead of lines.
+    pub fn absolute(resolution: u32) -> Self {
+        Self {
+            lines: resolution / QUADRATURE_EDGES,
+        }
+    }
+
+    pub fn pulses_per_rotation(&self) -> u32 {
+        self.lines * QUADRATURE_EDGES
+    }
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self { lines: 600 }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motor_geometry() {
+        assert_eq!(200, MotorConfig::STEP_1_8_DEGREE.steps_per_rotation());
+        assert_eq!(
+            50,
+            MotorConfig::STEP_1_8_DEGREE.electrical_cycles_per_rotation()
+        );
+        assert_eq!(400, MotorConfig::STEP_0_9_DEGREE.steps_per_rotation());
+        assert_eq!(
+            100,
+            MotorConfig::STEP_0_9_DEGREE.electrical_cycles_per_rotation()
+        );
+    }
+
+    #[test]
+    fn encoder_geometry() {
+        assert_eq!(2400, EncoderConfig::default().pulses_per_rotation());
+        assert_eq!(16384, EncoderConfig { lines: 4096 }.pulses_per_rotation());
+        assert_eq!(16384, EncoderConfig::absolute(16384).pulses_per_rotation());
+    }
+}
@@ -1,10 +1,23 @@
 use crate::pid::{Controller, PIDController};
 use crate::util;
 
+/// How the bridge lets the current decay in the off time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecayMode {
+    /// Recirculating through the low side, the current falls slowly.
+    Slow,
+    /// Against the supply, the current falls quickly.
+    Fast,
+    /// Fast at the start of the off time, then slow.
+    Mixed,
+}
+
 pub trait CurrentOutput {
     fn set_output_value(&mut self, value: i32);
     fn enable(&mut self, enable: bool);
     fn get_max_output_value(&mut self) -> i32;
+    /// Only called on a change. Ignored by bridges without decay modes.
+    fn set_decay_mode(&mut self, _mode: DecayMode) {}
 }
 
 pub trait CurrentDevice {
@@ -13,6 +26,14 @@ pub trait CurrentDevice {
     fn current(&self) -> i32;
     fn enable(&mut self, enable: bool);
     fn force_duty(&mut self, duty: i32);
+    /// The output is at its limit, so the current can not be reached.
+    fn is_saturated(&self) -> bool {
+        false
+    }
+    /// The measured ADC offset is not plausible, the output stays off.
+    fn is_sense_fault(&self) -> bool {
+        false
+    }
 }
 
 pub trait PIDControl {
@@ -21,45 +42,256 @@ pub trait PIDControl {
     fn set_controller_d(&mut self, value: i32);
 }
 
-const ADC_BUFFER_SIZE: usize = 1;
+/// How the current sense amplifier output relates to the current.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SenseMode {
+    /// Only the magnitude is measured, the sign follows the output.
+    Unipolar,
+    /// Zero current at the offset, a negative current below it.
+    Bipolar,
+}
+
+/// The current sense chain: shunt, amplifier and ADC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CurrentSense {
+    /// mOhm.
+    pub shunt_resistance: u32,
+    /// Amplifier gain, 1000 is a gain of 1.
+    pub gain: u32,
+    /// ADC reference, mV.
+    pub reference_voltage: u32,
+    pub adc_max_value: u32,
+    /// ADC value at zero current.
+    pub offset: u32,
+    pub mode: SenseMode,
+}
+
+impl CurrentSense {
+    /// 3.3 V reference and a gain of 6.8, the original board.
+    pub fn unipolar(shunt_resistance: u32, offset: u32, adc_max_value: u32) -> Self {
+        Self {
+            shunt_resistance,
+            gain: 6800,
+            reference_voltage: 3300,
+            adc_max_value,
+            offset,
+            mode: SenseMode::Unipolar,
+        }
+    }
+
+    /// Centered at half the reference.
+    pub fn bipolar(shunt_resistance: u32, gain: u32, adc_max_value: u32) -> Self {
+        Self {
+            shunt_resistance,
+            gain,
+            reference_voltage: 3300,
+            adc_max_value,
+            offset: adc_max_value / 2,
+            mode: SenseMode::Bipolar,
+        }
+    }
+
+    /// Voltage over the shunt in mV, only positive when unipolar.
+    pub fn voltage(&self, adc_value: u32) -> i32 {
+        let mut adc_value = adc_value as i64 - self.offset as i64;
+        if self.mode == SenseMode::Unipolar {
+            adc_value = adc_value.max(0);
+        }
+        let scaled_voltage = self.reference_voltage as i64 * adc_value / self.adc_max_value as i64;
+        (scaled_voltage * 1000 / self.gain as i64) as i32
+    }
+
+    /// mA, the magnitude when unipolar.
+    pub fn current(&self, adc_value: u32) -> i32 {
+        self.voltage(adc_value) * 1000 / self.shunt_resistance as i32 // uV / mOhm = mA
+    }
+}
+
+/// Measurement of the ADC offset with the output off, see
+/// `CurrentControl::start_zeroing`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZeroConfig {
+    /// Samples averaged for one measurement.
+    pub samples: u32,
+    /// Largest plausible distance from the offset of the `CurrentSense` as
+    /// configured, in ADC values. Further off is a hardware fault.
+    pub max_deviation: u32,
+    /// Measure before the output is enabled.
+    pub on_enable: bool,
+    /// Measure again after this many us disabled, 0 for never.
+    pub idle_interval: u32,
+}
+
+impl Default for ZeroConfig {
+    fn default() -> Self {
+        Self {
+            samples: 64,
+            max_deviation: 100,
+            on_enable: false,
+            idle_interval: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Zeroing {
+    sum: u32,
+    count: u32,
+}
+
+/// Where in the PWM period a sample was taken, see
+/// `CurrentControl::add_sample_at`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SamplePhase {
+    /// Middle of the on time of a center aligned PWM, the mean current.
+    #[default]
+    Center,
+    /// During the on time, this many output values after the switching edge.
+    OnTime { after_edge: i32 },
+}
+
+/// Timing of the current sampling, in output values, the PWM counter ticks
+/// of `CurrentOutput::get_max_output_value`. All zero takes every sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SamplingConfig {
+    /// Ringing after a switching edge, samples in it are discarded.
+    pub settle_time: i32,
+    /// ADC sample and hold time.
+    pub acquisition_time: i32,
+}
+
+/// Selection of the `DecayMode` from how much the current has to fall.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecayConfig {
+    /// mA the current has to fall for mixed decay.
+    pub mixed_threshold: i32,
+    /// mA the current has to fall for fast decay.
+    pub fast_threshold: i32,
+}
+
+impl Default for DecayConfig {
+    fn default() -> Self {
+        Self {
+            mixed_threshold: 50,
+            fast_threshold: 200,
+        }
+    }
+}
+
+/// Corrections of the output for the bridge, in output values. All zero
+/// leaves the output as the controller gives it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PulseConfig {
+    /// Output lost to the dead time, added in the direction of the current.
+    pub dead_time: i32,
+    /// mA around zero where the dead time compensation fades out, so the
+    /// loop does not hunt around the zero crossing.
+    pub dead_time_band: i32,
+    /// Shortest on and off pulse, in 1/1000 of the max output value. Shorter
+    /// pulses are rounded to none or to the shortest one.
+    pub min_pulse: i32,
+}
+
+/// Most samples a `CurrentFilter` can look back on.
+pub const MAX_FILTER_LENGTH: usize = 16;
+
+/// Filter of the ADC samples, see `CurrentControl::set_filter`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CurrentFilter {
+    /// The last sample.
+    #[default]
+    None,
+    /// Mean of the last `length` samples.
+    Boxcar { length: usize },
+    /// First order low pass, `weight` of a new sample, 1000 is no filtering.
+    Iir { weight: u32 },
+    /// Median of the last `length` samples, rejects single spikes.
+    Median { length: usize },
+}
+
 const PID_SCALING_FACTOR: i32 = 100_000;
 const PID_I_SCALE_FACTOR: i32 = 100;
 
 /// For now hard bound to ADC1
 pub struct CurrentControl<T: CurrentOutput> {
-    shunt_resistance: u32,
+    sense: CurrentSense,
+    zero_config: ZeroConfig,
+    /// The offset as configured, the center of the plausible window.
+    nominal_offset: u32,
+    zeroing: Option<Zeroing>,
+    sense_fault: bool,
+    enabled: bool,
+    idle_time: u32,
     current_setpoint: i32,
     adc_value: u32,
-    adc_offset: u32,
     voltage: i32,
     current: i32,
     output: T,
     output_value: i32,
     pid: PIDController<i32>,
-    adc_buffer: [u32; ADC_BUFFER_SIZE],
+    filter: CurrentFilter,
+    sampling: SamplingConfig,
+    decay: DecayConfig,
+    pulse: PulseConfig,
+    decay_mode: DecayMode,
+    /// The last sample was not discarded.
+    sample_valid: bool,
+    /// The current is estimated from the output, not measured.
+    estimated: bool,
+    /// Learned from the valid samples, mA per 1000 output values.
+    current_per_output: Option<i32>,
+    adc_buffer: [u32; MAX_FILTER_LENGTH],
     adc_buffer_index: usize,
-    adc_max_value: u32,
+    /// Samples in the buffer since the enable.
+    adc_count: usize,
+    /// Low pass of the samples, in 1/1000 ADC values.
+    adc_low_pass: u64,
     no_pid_control: bool,
+    saturated: bool,
 }
 
 impl<T: CurrentOutput> CurrentControl<T> {
+    /// With a unipolar current sense, see `CurrentSense::unipolar`.
     pub fn new(shunt_resistance: u32, output: T, adc_offset: u32, adc_max_value: u32) -> Self {
+        Self::with_sense(
+            output,
+            CurrentSense::unipolar(shunt_resistance, adc_offset, adc_max_value),
+        )
+    }
+
+    pub fn with_sense(output: T, sense: CurrentSense) -> Self {
         let mut s = Self {
-            shunt_resistance,
+            sense,
+            zero_config: ZeroConfig::default(),
+            nominal_offset: sense.offset,
+            zeroing: None,
+            sense_fault: false,
+            enabled: false,
+            idle_time: 0,
             current_setpoint: 0,
             adc_value: 0,
-            adc_offset,
             voltage: 0,
             current: 0,
             output,
             output_value: 0,
             pid: PIDController::new(0, 0, 0),
 
-            adc_buffer: [0; ADC_BUFFER_SIZE],
+            filter: CurrentFilter::default(),
+            sampling: SamplingConfig::default(),
+            decay: DecayConfig::default(),
+            pulse: PulseConfig::default(),
+            decay_mode: DecayMode::Slow,
+            sample_valid: true,
+            estimated: false,
+            current_per_output: None,
+            adc_buffer: [0; MAX_FILTER_LENGTH],
             adc_buffer_index: 0,
-            adc_max_value,
+            adc_count: 0,
+            adc_low_pass: 0,
             no_pid_control: false,
+            saturated: false,
         };
+        s.output.set_decay_mode(DecayMode::Slow);
         s.pid.set_limits(
             -s.output.get_max_output_value() * PID_SCALING_FACTOR,
             s.output.get_max_output_value() * PID_SCALING_FACTOR,
@@ -67,6 +299,87 @@ impl<T: CurrentOutput> CurrentControl<T> {
         s
     }
 
+    pub fn sense(&self) -> &CurrentSense {
+        &self.sense
+    }
+    pub fn set_sense(&mut self, sense: CurrentSense) {
+        self.sense = sense;
+        self.nominal_offset = sense.offset;
+    }
+    /// An offset measured before, e.g. on the drive of a recording. The
+    /// configured offset stays the center of the plausible window.
+    pub fn set_measured_offset(&mut self, offset: u32) {
+        self.sense.offset = offset;
+    }
+    pub fn filter(&self) -> &CurrentFilter {
+        &self.filter
+    }
+    /// Lengths are limited to `MAX_FILTER_LENGTH`.
+    pub fn set_filter(&mut self, filter: CurrentFilter) {
+        self.filter = filter;
+    }
+    pub fn sampling(&self) -> &SamplingConfig {
+        &self.sampling
+    }
+    pub fn set_sampling(&mut self, sampling: SamplingConfig) {
+        self.sampling = sampling;
+    }
+    pub fn decay(&self) -> &DecayConfig {
+        &self.decay
+    }
+    pub fn set_decay(&mut self, decay: DecayConfig) {
+        self.decay = decay;
+    }
+    pub fn pulse(&self) -> &PulseConfig {
+        &self.pulse
+    }
+    pub fn set_pulse(&mut self, pulse: PulseConfig) {
+        self.pulse = pulse;
+    }
+    pub fn decay_mode(&self) -> DecayMode {
+        self.decay_mode
+    }
+    pub fn zero_config(&self) -> &ZeroConfig {
+        &self.zero_config
+    }
+    pub fn set_zero_config(&mut self, zero_config: ZeroConfig) {
+        self.zero_config = zero_config;
+    }
+
+    /// Turn the output off and measure the ADC offset over the next samples.
+    /// The output comes back when enabled and the offset is plausible, a
+    /// sense fault is decided again.
+    pub fn start_zeroing(&mut self) {
+        self.zeroing = Some(Zeroing { sum: 0, count: 0 });
+        self.sense_fault = false;
+        self.output.enable(false);
+    }
+    pub fn is_zeroing(&self) -> bool {
+        self.zeroing.is_some()
+    }
+
+    fn finish_zeroing(&mut self, zeroing: Zeroing) {
+        let offset = zeroing.sum / zeroing.count;
+        self.zeroing = None;
+        self.sense_fault = offset.abs_diff(self.nominal_offset) > self.zero_config.max_deviation;
+        if !self.sense_fault {
+            self.sense.offset = offset;
+        }
+        if self.enabled {
+            self.enable_output();
+        }
+    }
+
+    fn enable_output(&mut self) {
+        self.output_value = 0;
+        self.adc_count = 0;
+        self.pid.reset();
+        self.no_pid_control = false;
+        if !self.sense_fault {
+            self.output.enable(true);
+        }
+    }
+
     pub fn adc_value(&self) -> u32 {
         self.adc_value
     }
@@ -83,51 +396,216 @@ impl<T: CurrentOutput> CurrentControl<T> {
         &mut self.output
     }
 
+    /// Smallest output value, either sign, with a valid sample at `phase`.
+    /// `None` when a sample there is never valid.
+    pub fn min_valid_duty(&self, phase: SamplePhase) -> Option<i32> {
+        let SamplingConfig {
+            settle_time,
+            acquisition_time,
+        } = self.sampling;
+        match phase {
+            SamplePhase::Center => Some(2 * settle_time + acquisition_time),
+            SamplePhase::OnTime { after_edge } if after_edge >= settle_time => {
+                Some(after_edge + acquisition_time)
+            }
+            SamplePhase::OnTime { .. } => None,
+        }
+    }
+
+    /// The current was estimated from the output at the last update, there
+    /// was no valid sample.
+    pub fn is_estimated(&self) -> bool {
+        self.estimated
+    }
+
+    /// A sample taken at `phase` of the PWM period. Discarded when too close
+    /// to a switching edge for the present output value.
+    pub fn add_sample_at(&mut self, adc_value: u32, phase: SamplePhase) {
+        let valid = match self.min_valid_duty(phase) {
+            Some(min_duty) => self.output_value.abs() >= min_duty,
+            None => false,
+        };
+        // With the output off for zeroing all samples count.
+        if valid || self.zeroing.is_some() {
+            self.add_sample(adc_value);
+        } else {
+            self.sample_valid = false;
+        }
+    }
+
     pub fn add_sample(&mut self, adc_value: u32) {
+        self.sample_valid = true;
+        if let Some(mut zeroing) = self.zeroing {
+            zeroing.sum = zeroing.sum.saturating_add(adc_value);
+            zeroing.count += 1;
+            if zeroing.count >= self.zero_config.samples.max(1) {
+                self.finish_zeroing(zeroing);
+            } else {
+                self.zeroing = Some(zeroing);
+            }
+        }
+
         self.adc_buffer[self.adc_buffer_index] = adc_value;
-        if self.adc_buffer_index < (ADC_BUFFER_SIZE - 1) {
-            self.adc_buffer_index += 1;
+        self.adc_buffer_index = (self.adc_buffer_index + 1) % MAX_FILTER_LENGTH;
+
+        let sample = adc_value as u64 * 1000;
+        self.adc_low_pass = if self.adc_count == 0 {
+            sample
         } else {
-            self.adc_buffer_index = 0;
-        }
+            let weight = match self.filter {
+                CurrentFilter::Iir { weight } => weight.clamp(1, 1000) as u64,
+                _ => 1000,
+            };
+            (self.adc_low_pass * (1000 - weight) + sample * weight) / 1000
+        };
+        self.adc_count = (self.adc_count + 1).min(MAX_FILTER_LENGTH);
     }
 
     fn calc_voltage(&mut self) {
-        let adc_value = (self.adc_value as i32 - self.adc_offset as i32).max(0) as u32;
-        let scaled_voltage = ((3300 * adc_value) / self.adc_max_value) as i32;
-        self.voltage = (scaled_voltage * 10) / 68;
+        self.voltage = self.sense.voltage(self.adc_value);
     }
 
     fn calc_current(&mut self) {
-        self.current = if self.voltage >= 0 {
-            let current_raw = (self.voltage * 1000) / self.shunt_resistance as i32; // uV / mOhm = mA
-            if self.output_value >= 0 {
-                current_raw
-            } else {
-                current_raw * -1
+        let current = self.sense.current(self.adc_value);
+        self.current = match self.sense.mode {
+            SenseMode::Bipolar => current,
+            SenseMode::Unipolar if self.output_value >= 0 => current,
+            SenseMode::Unipolar => -current,
+        }
+    }
+
+    /// Without a valid sample the current follows the output, with the ratio
+    /// of the valid samples. Holds the last current until there is one.
+    fn estimate_current(&mut self) {
+        if let Some(current_per_output) = self.current_per_output {
+            self.current = self.output_value * current_per_output / 1000;
+        }
+    }
+
+    fn learn_current_per_output(&mut self) {
+        if self.output_value == 0 {
+            return;
+        }
+        let current_per_output = self.current * 1000 / self.output_value;
+        self.current_per_output = Some(match self.current_per_output {
+            Some(learned) => (learned * 7 + current_per_output) / 8,
+            None => current_per_output,
+        });
+    }
+
+    /// The last `count` samples, newest first.
+    fn last_samples(&self, count: usize) -> impl Iterator<Item = u32> + '_ {
+        (1..=count).map(move |age| {
+            self.adc_buffer[(self.adc_buffer_index + MAX_FILTER_LENGTH - age) % MAX_FILTER_LENGTH]
+        })
+    }
+
+    fn filter_adc_value(&mut self) {
+        if self.adc_count == 0 {
+            // Nothing measured yet, no current.
+            self.adc_value = self.sense.offset;
+            return;
+        }
+        let length = |length: usize| length.clamp(1, MAX_FILTER_LENGTH).min(self.adc_count);
+        self.adc_value = match self.filter {
+            CurrentFilter::None => {
+                self.adc_buffer[(self.adc_buffer_index + MAX_FILTER_LENGTH - 1) % MAX_FILTER_LENGTH]
+            }
+            CurrentFilter::Boxcar { length: boxcar } => {
+                let length = length(boxcar);
+                self.last_samples(length).sum::<u32>() / length as u32
+            }
+            CurrentFilter::Iir { .. } => ((self.adc_low_pass + 500) / 1000) as u32,
+            CurrentFilter::Median { length: median } => {
+                let length = length(median);
+                let mut samples = [0; MAX_FILTER_LENGTH];
+                for (sample, value) in samples.iter_mut().zip(self.last_samples(length)) {
+                    *sample = value;
+                }
+                let samples = &mut samples[..length];
+                samples.sort_unstable();
+                samples[length / 2]
             }
+        };
+    }
+
+    /// Slow decay while the current rises or holds, faster the more it has
+    /// to fall. Through zero to the other sign it falls all the way.
+    fn select_decay_mode(&mut self) {
+        let fall = if self.current.signum() * self.current_setpoint.signum() < 0 {
+            self.current.abs()
         } else {
-            0
+            self.current.abs() - self.current_setpoint.abs()
+        };
+        let mode = if fall >= self.decay.fast_threshold {
+            DecayMode::Fast
+        } else if fall >= self.decay.mixed_threshold {
+            DecayMode::Mixed
+        } else {
+            DecayMode::Slow
+        };
+        if mode != self.decay_mode {
+            self.decay_mode = mode;
+            self.output.set_decay_mode(mode);
         }
     }
 
-    fn average_adc_value(&mut self) {
-        self.adc_value = self.adc_buffer.iter().sum::<u32>() / ADC_BUFFER_SIZE as u32;
+    fn dead_time_compensation(&self) -> i32 {
+        let PulseConfig {
+            dead_time,
+            dead_time_band,
+            ..
+        } = self.pulse;
+        let current = if self.current != 0 {
+            self.current
+        } else {
+            self.current_setpoint
+        };
+        if dead_time_band > 0 {
+            dead_time * util::clamp(-dead_time_band, dead_time_band, current) / dead_time_band
+        } else {
+            dead_time * current.signum()
+        }
+    }
+
+    /// Round pulses shorter than the minimum on either end of the range.
+    fn limit_pulses(&self, value: i32, max_output_value: i32) -> i32 {
+        let min_pulse = max_output_value * self.pulse.min_pulse / 1000;
+        let magnitude = value.abs();
+        let magnitude = if magnitude == 0 || magnitude >= min_pulse {
+            magnitude
+        } else if magnitude * 2 < min_pulse {
+            0
+        } else {
+            min_pulse
+        };
+        let off_time = max_output_value - magnitude;
+        let magnitude = if off_time == 0 || off_time >= min_pulse {
+            magnitude
+        } else if off_time * 2 < min_pulse {
+            max_output_value
+        } else {
+            max_output_value - min_pulse
+        };
+        magnitude * value.signum()
     }
 
     fn calc_output(&mut self, _dt: u32) {
         if !self.no_pid_control {
-            self.output_value = self.pid.update(
-                self.current * PID_SCALING_FACTOR as i32,
-                1 as i32,
-                PID_I_SCALE_FACTOR,
-            ) / PID_SCALING_FACTOR;
+            self.select_decay_mode();
+            self.output_value =
+                self.pid
+                    .update(self.current * PID_SCALING_FACTOR, 1, PID_I_SCALE_FACTOR)
+                    / PID_SCALING_FACTOR;
 
+            let max_output_value = self.output.get_max_output_value();
+            self.saturated = self.output_value.abs() >= max_output_value;
             self.output_value = util::clamp(
-                -self.output.get_max_output_value(),
-                self.output.get_max_output_value(),
-                self.output_value,
+                -max_output_value,
+                max_output_value,
+                self.output_value + self.dead_time_compensation(),
             );
+            self.output_value = self.limit_pulses(self.output_value, max_output_value);
         }
         self.output.set_output_value(self.output_value);
     }
@@ -135,33 +613,50 @@ impl<T: CurrentOutput> CurrentControl<T> {
 
 impl<T: CurrentOutput> CurrentDevice for CurrentControl<T> {
     fn update(&mut self, dt: u32) {
-        self.average_adc_value();
-        self.calc_voltage();
-        self.calc_current();
-        self.calc_output(dt);
+        if !self.enabled && self.zeroing.is_none() && self.zero_config.idle_interval > 0 {
+            self.idle_time = self.idle_time.saturating_add(dt);
+            if self.idle_time >= self.zero_config.idle_interval {
+                self.idle_time = 0;
+                self.start_zeroing();
+            }
+        }
+
+        self.estimated = !self.sample_valid;
+        if self.sample_valid {
+            self.filter_adc_value();
+            self.calc_voltage();
+            self.calc_current();
+            self.learn_current_per_output();
+        } else {
+            self.estimate_current();
+        }
+        if self.zeroing.is_none() && !self.sense_fault {
+            self.calc_output(dt);
+        }
     }
     fn set_current(&mut self, milli_amps: i32) {
         self.current_setpoint = milli_amps;
         self.pid.set_target(milli_amps * PID_SCALING_FACTOR);
     }
     fn current(&self) -> i32 {
-        if self.output_value >= 0 {
-            self.current as i32
-        } else {
-            -1 * self.current as i32
-        }
+        self.current
     }
     fn enable(&mut self, enable: bool) {
-        if enable {
-            //reset
-            self.output_value = 0;
-            for data in self.adc_buffer.iter_mut() {
-                *data = 0;
-            }
-            self.pid.reset();
-            self.no_pid_control = false;
+        self.enabled = enable;
+        self.idle_time = 0;
+        if !enable {
+            self.output.enable(false);
+        } else if self.zero_config.on_enable {
+            self.start_zeroing();
+        } else if self.zeroing.is_none() {
+            self.enable_output();
         }
-        self.output.enable(enable);
+    }
+    fn is_saturated(&self) -> bool {
+        self.saturated
+    }
+    fn is_sense_fault(&self) -> bool {
+        self.sense_fault
     }
     fn force_duty(&mut self, duty: i32) {
         self.no_pid_control = true;
@@ -190,21 +685,25 @@ impl<T: CurrentOutput> PIDControl for CurrentControl<T> {
 mod tests {
     use super::*;
 
+    #[derive(Default)]
     struct MockCurrentOutput {
         last_output: i32,
-    }
-    impl Default for MockCurrentOutput {
-        fn default() -> Self {
-            Self { last_output: 0 }
-        }
+        enabled: bool,
+        decay_mode: Option<DecayMode>,
     }
 
     impl CurrentOutput for MockCurrentOutput {
         fn set_output_value(&mut self, value: i32) {
             self.last_output = value;
         }
-        fn enable(&mut self, _enable: bool) {
-            // Nothing to do
+        fn enable(&mut self, enable: bool) {
+            self.enabled = enable;
+        }
+        fn get_max_output_value(&mut self) -> i32 {
+            1000
+        }
+        fn set_decay_mode(&mut self, mode: DecayMode) {
+            self.decay_mode = Some(mode);
         }
     }
 
@@ -213,22 +712,351 @@ mod tests {
         let mock_current_ouput = MockCurrentOutput::default();
 
         let shunt_resistance = 400;
-        let target_current_mA = 10;
+        let target_current_ma = 10;
         let mut currentcontrol =
             CurrentControl::new(shunt_resistance, mock_current_ouput, 0, 2_u32.pow(12));
-        currentcontrol.set_current(target_current_mA);
+        currentcontrol.set_current(target_current_ma);
         currentcontrol.set_controller_p(10);
         currentcontrol.set_controller_i(0);
         currentcontrol.set_controller_d(0);
         currentcontrol.enable(true);
 
         //for _ in 0..3 {
-        let mV = 1;
+        let mv = 1;
+        currentcontrol.add_sample(9); // 3300 * 9 / 4096 * 10 / 68 = 1 mV
         currentcontrol.update(1);
         //}
 
-        let current_mA = mV * 1000 / shunt_resistance as i32;
-        let output = (target_current_mA - current_mA) * 10;
+        let current_ma = mv * 1000 / shunt_resistance as i32;
+        let output = (target_current_ma - current_ma) * 10;
         assert_eq!(output, currentcontrol.get_current_output().last_output);
     }
+
+    #[test]
+    fn bipolar_sense() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        assert_eq!(0, sense.current(2048));
+        // 3300 * 124 / 4096 = 99 mV, / 20 = 4 mV over the shunt.
+        assert_eq!(-40, sense.current(2048 - 124));
+        assert_eq!(40, sense.current(2048 + 124));
+
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_controller_p(10);
+        currentcontrol.enable(true);
+
+        // Measured, not taken from the output.
+        currentcontrol.add_sample(2048 - 124);
+        currentcontrol.update(1);
+        assert_eq!(-40, currentcontrol.current());
+        assert_eq!(400, currentcontrol.get_current_output().last_output);
+        currentcontrol.update(1);
+        assert_eq!(-40, currentcontrol.current());
+    }
+
+    #[test]
+    fn unipolar_sign_follows_output() {
+        let sense = CurrentSense::unipolar(100, 0, 4096);
+        assert_eq!(0, sense.current(0));
+        // The offset is the lowest value.
+        assert_eq!(0, CurrentSense::unipolar(100, 50, 4096).current(10));
+
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_controller_p(10);
+        currentcontrol.enable(true);
+        currentcontrol.set_current(-100);
+        currentcontrol.update(1);
+        assert!(currentcontrol.output_value() < 0);
+
+        // 3300 * 844 / 4096 = 679 mV, / 6.8 = 99 mV over the shunt.
+        currentcontrol.add_sample(844);
+        currentcontrol.update(1);
+        assert_eq!(-990, currentcontrol.current());
+    }
+
+    #[test]
+    fn zero_on_enable() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_zero_config(ZeroConfig {
+            samples: 4,
+            on_enable: true,
+            ..ZeroConfig::default()
+        });
+        currentcontrol.set_controller_p(10);
+        currentcontrol.set_current(100);
+        currentcontrol.enable(true);
+        assert!(currentcontrol.is_zeroing());
+
+        for sample in [2060, 2070, 2060, 2070] {
+            assert!(!currentcontrol.get_current_output().enabled);
+            currentcontrol.update(50);
+            assert_eq!(0, currentcontrol.get_current_output().last_output);
+            currentcontrol.add_sample(sample);
+        }
+        assert!(!currentcontrol.is_zeroing());
+        assert!(!currentcontrol.is_sense_fault());
+        assert_eq!(2065, currentcontrol.sense().offset);
+        assert!(currentcontrol.get_current_output().enabled);
+
+        currentcontrol.update(50);
+        assert_eq!(0, currentcontrol.current());
+        assert_eq!(1000, currentcontrol.get_current_output().last_output);
+    }
+
+    #[test]
+    fn implausible_offset_is_fault() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_zero_config(ZeroConfig {
+            samples: 2,
+            on_enable: true,
+            ..ZeroConfig::default()
+        });
+        currentcontrol.enable(true);
+        // A shorted amplifier output.
+        currentcontrol.add_sample(0);
+        currentcontrol.add_sample(0);
+
+        assert!(currentcontrol.is_sense_fault());
+        assert_eq!(2048, currentcontrol.sense().offset);
+        assert!(!currentcontrol.get_current_output().enabled);
+
+        // Measured again on the next enable.
+        currentcontrol.enable(false);
+        currentcontrol.enable(true);
+        assert!(!currentcontrol.is_sense_fault());
+        currentcontrol.add_sample(2040);
+        currentcontrol.add_sample(2040);
+        assert!(!currentcontrol.is_sense_fault());
+        assert!(currentcontrol.get_current_output().enabled);
+    }
+
+    #[test]
+    fn zero_again_when_idle() {
+        let sense = CurrentSense::unipolar(100, 10, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_zero_config(ZeroConfig {
+            samples: 2,
+            idle_interval: 1000,
+            ..ZeroConfig::default()
+        });
+        currentcontrol.enable(false);
+        for _ in 0..19 {
+            currentcontrol.update(50);
+        }
+        assert!(!currentcontrol.is_zeroing());
+        currentcontrol.update(50);
+        assert!(currentcontrol.is_zeroing());
+
+        currentcontrol.add_sample(30);
+        currentcontrol.add_sample(32);
+        assert_eq!(31, currentcontrol.sense().offset);
+        // Stays off.
+        assert!(!currentcontrol.get_current_output().enabled);
+
+        // Not while enabled.
+        currentcontrol.enable(true);
+        for _ in 0..40 {
+            currentcontrol.update(50);
+        }
+        assert!(!currentcontrol.is_zeroing());
+    }
+
+    #[test]
+    fn boxcar_filter() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_filter(CurrentFilter::Boxcar { length: 4 });
+        currentcontrol.enable(true);
+        // No samples yet, no current.
+        currentcontrol.update(50);
+        assert_eq!(2048, currentcontrol.adc_value());
+
+        // The mean of the samples so far, then of the last 4.
+        currentcontrol.add_sample(100);
+        currentcontrol.update(50);
+        assert_eq!(100, currentcontrol.adc_value());
+        for sample in [200, 300, 400, 500] {
+            currentcontrol.add_sample(sample);
+        }
+        currentcontrol.update(50);
+        assert_eq!(350, currentcontrol.adc_value());
+
+        // Starts over on enable.
+        currentcontrol.enable(true);
+        currentcontrol.add_sample(10);
+        currentcontrol.update(50);
+        assert_eq!(10, currentcontrol.adc_value());
+    }
+
+    #[test]
+    fn iir_filter() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_filter(CurrentFilter::Iir { weight: 250 });
+        currentcontrol.enable(true);
+        currentcontrol.add_sample(1000);
+        currentcontrol.add_sample(2000);
+        currentcontrol.update(50);
+        assert_eq!(1250, currentcontrol.adc_value());
+        currentcontrol.add_sample(2000);
+        currentcontrol.update(50);
+        assert_eq!(1438, currentcontrol.adc_value());
+    }
+
+    #[test]
+    fn median_filter_rejects_spike() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_filter(CurrentFilter::Median { length: 3 });
+        currentcontrol.enable(true);
+        for sample in [2100, 4095, 2110] {
+            currentcontrol.add_sample(sample);
+        }
+        currentcontrol.update(50);
+        assert_eq!(2110, currentcontrol.adc_value());
+
+        // Longer than the buffer.
+        currentcontrol.set_filter(CurrentFilter::Median { length: 100 });
+        for sample in 0..20 {
+            currentcontrol.add_sample(sample);
+        }
+        currentcontrol.update(50);
+        assert_eq!(12, currentcontrol.adc_value());
+    }
+
+    #[test]
+    fn min_valid_duty() {
+        let mut currentcontrol = CurrentControl::new(100, MockCurrentOutput::default(), 0, 4096);
+        assert_eq!(Some(0), currentcontrol.min_valid_duty(SamplePhase::Center));
+        currentcontrol.set_sampling(SamplingConfig {
+            settle_time: 20,
+            acquisition_time: 10,
+        });
+        assert_eq!(Some(50), currentcontrol.min_valid_duty(SamplePhase::Center));
+        assert_eq!(
+            Some(40),
+            currentcontrol.min_valid_duty(SamplePhase::OnTime { after_edge: 30 })
+        );
+        assert_eq!(
+            None,
+            currentcontrol.min_valid_duty(SamplePhase::OnTime { after_edge: 10 })
+        );
+    }
+
+    #[test]
+    fn estimate_current_at_low_duty() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_sampling(SamplingConfig {
+            settle_time: 20,
+            acquisition_time: 10,
+        });
+        currentcontrol.set_controller_p(1);
+        currentcontrol.enable(true);
+
+        // The output is off, nothing to measure.
+        currentcontrol.add_sample_at(3000, SamplePhase::Center);
+        currentcontrol.update(50);
+        assert!(currentcontrol.is_estimated());
+        assert_eq!(0, currentcontrol.current());
+
+        // 400 mA at an output of 200, valid.
+        currentcontrol.set_current(200);
+        currentcontrol.update(50);
+        assert_eq!(200, currentcontrol.output_value());
+        currentcontrol.set_current(360);
+        currentcontrol.add_sample_at(2048 + 993, SamplePhase::Center);
+        currentcontrol.update(50);
+        assert!(!currentcontrol.is_estimated());
+        assert_eq!(400, currentcontrol.current());
+
+        // An output of -40 is too short to sample, 2 mA per output value.
+        assert_eq!(-40, currentcontrol.output_value());
+        currentcontrol.add_sample_at(0, SamplePhase::Center);
+        currentcontrol.update(50);
+        assert!(currentcontrol.is_estimated());
+        assert_eq!(-80, currentcontrol.current());
+        assert_eq!(440, currentcontrol.output_value());
+    }
+
+    #[test]
+    fn decay_mode_follows_falling_current() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        assert_eq!(
+            Some(DecayMode::Slow),
+            currentcontrol.get_current_output().decay_mode
+        );
+        currentcontrol.enable(true);
+        // 400 mA.
+        currentcontrol.add_sample(2048 + 993);
+
+        let mut decay_mode = |setpoint| {
+            currentcontrol.set_current(setpoint);
+            currentcontrol.update(50);
+            assert_eq!(
+                Some(currentcontrol.decay_mode()),
+                currentcontrol.get_current_output().decay_mode
+            );
+            currentcontrol.decay_mode()
+        };
+        assert_eq!(DecayMode::Slow, decay_mode(800));
+        assert_eq!(DecayMode::Slow, decay_mode(380));
+        assert_eq!(DecayMode::Mixed, decay_mode(300));
+        assert_eq!(DecayMode::Fast, decay_mode(100));
+        // Through zero.
+        assert_eq!(DecayMode::Fast, decay_mode(-10));
+    }
+
+    #[test]
+    fn dead_time_compensation() {
+        let sense = CurrentSense::bipolar(100, 20_000, 4096);
+        let mut currentcontrol = CurrentControl::with_sense(MockCurrentOutput::default(), sense);
+        currentcontrol.set_pulse(PulseConfig {
+            dead_time: 30,
+            ..PulseConfig::default()
+        });
+        currentcontrol.set_controller_p(1);
+        currentcontrol.enable(true);
+
+        // At zero current in the direction of the setpoint.
+        currentcontrol.set_current(-10);
+        currentcontrol.add_sample(2048);
+        currentcontrol.update(50);
+        assert_eq!(-40, currentcontrol.output_value());
+
+        // 400 mA.
+        currentcontrol.set_current(400);
+        currentcontrol.add_sample(2048 + 993);
+        currentcontrol.update(50);
+        assert_eq!(30, currentcontrol.output_value());
+
+        // Less within the band.
+        currentcontrol.set_pulse(PulseConfig {
+            dead_time: 30,
+            dead_time_band: 800,
+            ..PulseConfig::default()
+        });
+        currentcontrol.update(50);
+        assert_eq!(15, currentcontrol.output_value());
+    }
+
+    #[test]
+    fn minimum_pulse() {
+        let mut currentcontrol = CurrentControl::new(100, MockCurrentOutput::default(), 0, 4096);
+        currentcontrol.set_pulse(PulseConfig {
+            min_pulse: 20,
+            ..PulseConfig::default()
+        });
+        // 20 of 1000.
+        let limit = |value| currentcontrol.limit_pulses(value, 1000);
+        assert_eq!(0, limit(0));
+        assert_eq!(0, limit(9));
+        assert_eq!(-20, limit(-10));
+        assert_eq!(25, limit(25));
+        assert_eq!(980, limit(985));
+        assert_eq!(-1000, limit(-995));
+        assert_eq!(1000, limit(1000));
+    }
 }
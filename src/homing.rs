@@ -0,0 +1,311 @@
+use crate::multi_turn::MultiTurnPosition;
+use crate::switches::Side;
+
+/// Distance in pulses at which a move is done.
+const POSITION_TOLERANCE: i64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HomingMethod {
+    /// Home on the limit switch at the homing direction.
+    LimitSwitch,
+    /// Home on a hard stop, detected by a following error above
+    /// `max_following_error` or a saturated current loop, for `stall_cycles`
+    /// updates in a row.
+    Sensorless {
+        max_following_error: i64,
+        stall_cycles: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HomingConfig {
+    pub method: HomingMethod,
+    pub direction: Side,
+    /// Speed while searching the switch or stop, in pulses per second.
+    pub search_speed: i32,
+    /// Speed of the second, slow approach, in pulses per second.
+    pub approach_speed: i32,
+    /// Pulses to move back before the slow approach.
+    pub back_off: i64,
+    /// The zero position is `offset` pulses from the switch or stop.
+    pub offset: i64,
+    /// Give up if nothing is found within this many pulses.
+    pub max_travel: i64,
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        Self {
+            method: HomingMethod::LimitSwitch,
+            direction: Side::Negative,
+            search_speed: 2400,
+            approach_speed: 400,
+            back_off: 600,
+            offset: 0,
+            max_travel: i64::MAX,
+        }
+    }
+}
+
+/// The measurements homing decides on.
+pub struct HomingInput {
+    pub position: MultiTurnPosition,
+    pub following_error: i64,
+    pub limit_active: bool,
+    pub current_saturated: bool,
+}
+
+/// What the motor control has to do next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HomingAction {
+    /// Move with a velocity in pulses per second.
+    Jog(i32),
+    MoveTo(MultiTurnPosition),
+    /// Make the position the zero position.
+    SetHome(MultiTurnPosition),
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Idle,
+    Search { start: MultiTurnPosition },
+    BackOff { target: MultiTurnPosition },
+    Approach { start: MultiTurnPosition },
+    HomeSet,
+    MoveToZero,
+    Done,
+    Failed,
+}
+
+/// Homing state machine: search the switch or stop, back off, approach it
+/// again slowly and set the zero position.
+pub struct Homing {
+    config: HomingConfig,
+    phase: Phase,
+    stall_count: u32,
+}
+
+impl Homing {
+    pub fn new(config: HomingConfig) -> Self {
+        Self {
+            config,
+            phase: Phase::Idle,
+            stall_count: 0,
+        }
+    }
+    pub fn config(&self) -> &HomingConfig {
+        &self.config
+    }
+    pub fn set_config(&mut self, config: HomingConfig) {
+        self.config = config;
+    }
+    pub fn is_active(&self) -> bool {
+        !matches!(self.phase, Phase::Idle | Phase::Done | Phase::Failed)
+    }
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+    pub fn has_failed(&self) -> bool {
+        self.phase == Phase::Failed
+    }
+
+    pub fn start(&mut self, position: MultiTurnPosition) -> HomingAction {
+        self.stall_count = 0;
+        self.phase = Phase::Search { start: position };
+        HomingAction::Jog(self.config.direction.sign() * self.config.search_speed.abs())
+    }
+
+    pub fn update(&mut self, input: &HomingInput) -> Option<HomingAction> {
+        let triggered = self.triggered(input);
+        let sign = self.config.direction.sign() as i64;
+
+        match self.phase {
+            Phase::Search { start } => {
+                if triggered {
+                    let target = input.position.saturating_add(-sign * self.config.back_off);
+                    self.next_phase(Phase::BackOff { target });
+                    Some(HomingAction::MoveTo(target))
+                } else if self.travelled_too_far(start, input.position) {
+                    self.fail()
+                } else {
+                    None
+                }
+            }
+            Phase::BackOff { target } => {
+                if input.position.saturating_distance(target).abs() > POSITION_TOLERANCE {
+                    None
+                } else if self.config.method == HomingMethod::LimitSwitch && input.limit_active {
+                    // Did not get off the switch.
+                    self.fail()
+                } else {
+                    self.next_phase(Phase::Approach {
+                        start: input.position,
+                    });
+                    Some(HomingAction::Jog(
+                        self.config.direction.sign() * self.config.approach_speed.abs(),
+                    ))
+                }
+            }
+            Phase::Approach { start } => {
+                if triggered {
+                    self.next_phase(Phase::HomeSet);
+                    Some(HomingAction::SetHome(
+                        input.position.saturating_add(self.config.offset),
+                    ))
+                } else if self.travelled_too_far(start, input.position) {
+                    self.fail()
+                } else {
+                    None
+                }
+            }
+            Phase::HomeSet => {
+                self.next_phase(Phase::MoveToZero);
+                Some(HomingAction::MoveTo(MultiTurnPosition::ZERO))
+            }
+            Phase::MoveToZero => {
+                if input.position.pulses().abs() <= POSITION_TOLERANCE {
+                    self.next_phase(Phase::Done);
+                    Some(HomingAction::Done)
+                } else {
+                    None
+                }
+            }
+            Phase::Idle | Phase::Done | Phase::Failed => None,
+        }
+    }
+
+    fn triggered(&mut self, input: &HomingInput) -> bool {
+        match self.config.method {
+            HomingMethod::LimitSwitch => input.limit_active,
+            HomingMethod::Sensorless {
+                max_following_error,
+                stall_cycles,
+            } => {
+                if input.following_error.abs() > max_following_error || input.current_saturated {
+                    self.stall_count = self.stall_count.saturating_add(1);
+                } else {
+                    self.stall_count = 0;
+                }
+                self.stall_count >= stall_cycles
+            }
+        }
+    }
+
+    fn travelled_too_far(&self, start: MultiTurnPosition, position: MultiTurnPosition) -> bool {
+        position.saturating_distance(start).abs() > self.config.max_travel
+    }
+
+    fn next_phase(&mut self, phase: Phase) {
+        self.stall_count = 0;
+        self.phase = phase;
+    }
+
+    fn fail(&mut self) -> Option<HomingAction> {
+        self.next_phase(Phase::Failed);
+        Some(HomingAction::Failed)
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(position: i64, limit_active: bool) -> HomingInput {
+        HomingInput {
+            position: MultiTurnPosition::from_pulses(position),
+            following_error: 0,
+            limit_active,
+            current_saturated: false,
+        }
+    }
+
+    #[test]
+    fn limit_switch_homing() {
+        let mut homing = Homing::new(HomingConfig {
+            offset: 50,
+            back_off: 100,
+            ..HomingConfig::default()
+        });
+
+        assert_eq!(HomingAction::Jog(-2400), homing.start(1000.into()));
+        assert_eq!(None, homing.update(&input(500, false)));
+
+        // Switch found, back off.
+        assert_eq!(
+            Some(HomingAction::MoveTo(400.into())),
+            homing.update(&input(300, true))
+        );
+        assert_eq!(None, homing.update(&input(350, false)));
+        assert_eq!(
+            Some(HomingAction::Jog(-400)),
+            homing.update(&input(399, false))
+        );
+
+        // Found again, slowly.
+        assert_eq!(None, homing.update(&input(320, false)));
+        assert_eq!(
+            Some(HomingAction::SetHome(360.into())),
+            homing.update(&input(310, true))
+        );
+        assert_eq!(
+            Some(HomingAction::MoveTo(MultiTurnPosition::ZERO)),
+            homing.update(&input(-50, true))
+        );
+        assert_eq!(Some(HomingAction::Done), homing.update(&input(1, false)));
+        assert!(homing.is_done());
+    }
+
+    #[test]
+    fn sensorless_homing() {
+        let mut homing = Homing::new(HomingConfig {
+            method: HomingMethod::Sensorless {
+                max_following_error: 20,
+                stall_cycles: 3,
+            },
+            direction: Side::Positive,
+            ..HomingConfig::default()
+        });
+        homing.start(0.into());
+
+        let mut stalled = input(1000, false);
+        stalled.following_error = 30;
+        assert_eq!(None, homing.update(&stalled));
+        assert_eq!(None, homing.update(&stalled));
+        assert_eq!(
+            Some(HomingAction::MoveTo(400.into())),
+            homing.update(&stalled)
+        );
+
+        // A saturated current loop is a stall as well.
+        homing.update(&input(400, false));
+        let mut saturated = input(1000, false);
+        saturated.current_saturated = true;
+        homing.update(&saturated);
+        homing.update(&saturated);
+        assert_eq!(
+            Some(HomingAction::SetHome(1000.into())),
+            homing.update(&saturated)
+        );
+    }
+
+    #[test]
+    fn nothing_found() {
+        let mut homing = Homing::new(HomingConfig {
+            max_travel: 1000,
+            ..HomingConfig::default()
+        });
+        homing.start(0.into());
+        assert_eq!(None, homing.update(&input(-1000, false)));
+        assert_eq!(
+            Some(HomingAction::Failed),
+            homing.update(&input(-1001, false))
+        );
+        assert!(homing.has_failed());
+    }
+}
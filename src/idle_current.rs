@@ -0,0 +1,184 @@
+use crate::multi_turn::MultiTurnPosition;
+
+/// Fixed point scale of the current level, in percent.
+const LEVEL_SCALE: i32 = 1000;
+const FULL_LEVEL: i32 = 100 * LEVEL_SCALE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdleConfig {
+    /// Standstill time before the current is reduced.
+    pub standstill_ms: u32,
+    /// Current at standstill, in percent of the configured current. 100
+    /// disables the reduction.
+    pub idle_percent: i32,
+    /// Time to ramp from full current down to zero.
+    pub ramp_down_ms: u32,
+    /// Time to ramp from zero up to full current.
+    pub ramp_up_ms: u32,
+    /// Encoder motion in pulses that counts as the load being pushed.
+    pub wake_pulses: i64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            standstill_ms: 1000,
+            idle_percent: 50,
+            ramp_down_ms: 200,
+            ramp_up_ms: 2,
+            wake_pulses: 4,
+        }
+    }
+}
+
+/// Reduces the coil current after a standstill, restores it on motion.
+pub struct IdleCurrent {
+    config: IdleConfig,
+    standstill_us: u32,
+    reference: MultiTurnPosition,
+    level: i32,
+}
+
+impl IdleCurrent {
+    pub fn new(config: IdleConfig) -> Self {
+        Self {
+            config,
+            standstill_us: 0,
+            reference: MultiTurnPosition::ZERO,
+            level: FULL_LEVEL,
+        }
+    }
+    pub fn config(&self) -> &IdleConfig {
+        &self.config
+    }
+    pub fn set_config(&mut self, config: IdleConfig) {
+        self.config = config;
+    }
+    /// The current is (being) reduced.
+    pub fn is_idle(&self) -> bool {
+        self.level < FULL_LEVEL
+    }
+    /// Current level in percent.
+    pub fn percent(&self) -> i32 {
+        self.level / LEVEL_SCALE
+    }
+    /// `current` reduced to the current level.
+    pub fn scale(&self, current: i32) -> i32 {
+        (current as i64 * self.level as i64 / FULL_LEVEL as i64) as i32
+    }
+
+    /// Restart the standstill time, on a new command.
+    pub fn restart(&mut self) {
+        self.standstill_us = 0;
+    }
+
+    /// `elapsed_us` is the time since the previous update. A changed setpoint
+    /// restarts the standstill time and ramps the current back up.
+    pub fn update(&mut self, elapsed_us: u32, position: MultiTurnPosition, setpoint_changed: bool) {
+        let pushed = position.saturating_distance(self.reference).abs() > self.config.wake_pulses;
+        if setpoint_changed || pushed {
+            self.standstill_us = 0;
+            self.reference = position;
+        } else {
+            self.standstill_us = self.standstill_us.saturating_add(elapsed_us);
+        }
+
+        let target = if self.standstill_us >= self.config.standstill_ms.saturating_mul(1000) {
+            self.config.idle_percent.clamp(0, 100) * LEVEL_SCALE
+        } else {
+            FULL_LEVEL
+        };
+        let ramp_ms = if target < self.level {
+            self.config.ramp_down_ms
+        } else {
+            self.config.ramp_up_ms
+        };
+        let step = if ramp_ms == 0 {
+            FULL_LEVEL
+        } else {
+            (elapsed_us as i64 * FULL_LEVEL as i64 / (ramp_ms as i64 * 1000))
+                .clamp(1, FULL_LEVEL as i64) as i32
+        };
+
+        self.level = if target < self.level {
+            (self.level - step).max(target)
+        } else {
+            (self.level + step).min(target)
+        };
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idle_current() -> IdleCurrent {
+        IdleCurrent::new(IdleConfig {
+            standstill_ms: 10,
+            idle_percent: 40,
+            ramp_down_ms: 6,
+            ramp_up_ms: 1,
+            wake_pulses: 2,
+        })
+    }
+
+    #[test]
+    fn ramps_down_after_standstill() {
+        let mut idle = idle_current();
+        for _ in 0..9 {
+            idle.update(1000, 0.into(), false);
+        }
+        assert!(!idle.is_idle());
+
+        idle.update(1000, 0.into(), false);
+        assert_eq!(83, idle.percent());
+        for _ in 0..5 {
+            idle.update(1000, 0.into(), false);
+        }
+        assert_eq!(40, idle.percent());
+        idle.update(1000, 0.into(), false);
+        assert_eq!(40, idle.percent());
+        assert_eq!(400, idle.scale(1000));
+    }
+
+    #[test]
+    fn restores_on_command_and_push() {
+        let mut idle = idle_current();
+        for _ in 0..20 {
+            idle.update(1000, 0.into(), false);
+        }
+        assert_eq!(40, idle.percent());
+
+        // Smoothly back up.
+        idle.update(500, 0.into(), true);
+        assert_eq!(90, idle.percent());
+        idle.update(500, 0.into(), false);
+        assert_eq!(100, idle.percent());
+
+        for _ in 0..20 {
+            idle.update(1000, 0.into(), false);
+        }
+        // Within the noise.
+        idle.update(1000, 2.into(), false);
+        assert_eq!(40, idle.percent());
+        // Pushed.
+        idle.update(1000, (-3).into(), false);
+        assert_eq!(100, idle.percent());
+    }
+
+    #[test]
+    fn disabled_at_100_percent() {
+        let mut idle = IdleCurrent::new(IdleConfig {
+            idle_percent: 100,
+            ..IdleConfig::default()
+        });
+        for _ in 0..100 {
+            idle.update(100_000, 0.into(), false);
+        }
+        assert!(!idle.is_idle());
+    }
+}
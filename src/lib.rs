@@ -1,11 +1,27 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "sim")), no_std)]
 
+pub mod absolute_encoder;
+pub mod adaptive_current;
 pub mod calibration;
+pub mod cogging;
 pub mod coil;
+pub mod config;
 pub mod current_control;
+pub mod homing;
+pub mod idle_current;
 pub mod motor_control;
+pub mod multi_turn;
 pub mod pid;
 pub mod position_control;
+pub mod quadrature;
+pub mod replay;
 pub mod serial_commands;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 pub mod sine_lookup;
+pub mod soft_limits;
+pub mod stall;
+pub mod stop;
+pub mod switches;
 pub mod util;
+pub mod waveform;
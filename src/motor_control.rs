@@ -1,27 +1,85 @@
+use crate::adaptive_current::{AdaptiveConfig, AdaptiveCurrent};
+use crate::calibration::DEFAULT_TABLE_SIZE;
 use crate::coil::Coil;
+use crate::config::{EncoderConfig, MotorConfig};
 use crate::current_control::{CurrentDevice, PIDControl};
-use crate::position_control::{PositionControl, PositionInput};
+use crate::homing::{Homing, HomingAction, HomingConfig, HomingInput};
+use crate::idle_current::{IdleConfig, IdleCurrent};
+use crate::multi_turn::MultiTurnPosition;
+use crate::position_control::{PositionControl, PositionInput, PositionTables, TableStorage};
+use crate::sine_lookup::angle::ElectricalAngle;
+use crate::soft_limits::{LimitViolation, SoftLimits};
+use crate::stall::{StallConfig, StallDetector, StallEvent};
+use crate::stop::{AfterStop, StopCategory, StopConfig, VelocityRamp};
+use crate::switches::{NoSwitches, Side, SwitchInput};
+use crate::waveform::{Waveform, WaveformTable};
 //use crate::pid::{Controller, PIDController};
 
-const DWT_FREQ: i32 = 72_000_000;
-const UPDATE_PERIOD: i32 = DWT_FREQ / 20_000;
+pub(crate) const DWT_FREQ: i32 = 72_000_000;
+const UPDATE_FREQUENCY: i32 = 20_000;
+const UPDATE_PERIOD: i32 = DWT_FREQ / UPDATE_FREQUENCY;
 
 pub trait PositionControlled {
     fn set_angle(&mut self, degrees: i32);
+    fn set_electrical_angle(&mut self, angle: ElectricalAngle);
     fn get_angle(&self) -> i32;
 }
 
-pub struct MotorControl<T1, T2, Inp>
-where
+/// Snapshot of the drive state, for the serial status.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub enabled: bool,
+    pub estop_latched: bool,
+    pub stall_count: u32,
+    pub stall_fault: bool,
+    pub sense_fault: bool,
+    pub position: MultiTurnPosition,
+    pub following_error: i64,
+}
+
+/// `N` is the size of the calibration table, see `Calibration`, `Tables`
+/// its storage, see `TableStorage`.
+pub struct MotorControl<
+    T1,
+    T2,
+    Inp,
+    Sw = NoSwitches,
+    const N: usize = DEFAULT_TABLE_SIZE,
+    Tables = &'static mut PositionTables<N>,
+> where
     T1: CurrentDevice,
     T2: CurrentDevice,
+    Tables: TableStorage<N>,
 {
     coil_a: Coil<T1>,
     coil_b: Coil<T2>,
-    position_control: PositionControl<Inp>,
+    position_control: PositionControl<Inp, N, Tables>,
+    switches: Sw,
+    homing: Homing,
+    soft_limits: SoftLimits,
+    limit_violation: Option<LimitViolation>,
+    limit_reported: bool,
+    jog_velocity: Option<i32>,
+    stop_config: StopConfig,
+    stop_ramp: VelocityRamp,
+    estop_category: StopCategory,
+    estop_latched: bool,
+    /// From either coil, see `CurrentDevice::is_sense_fault`.
+    sense_fault: bool,
+    stop_disables: bool,
+    stop_hold_updates: u32,
+    current_percent: i32,
+    idle_current: IdleCurrent,
+    adaptive_current: Option<AdaptiveCurrent>,
+    idle_setpoint: MultiTurnPosition,
+    stall_detector: StallDetector,
+    waveform: Waveform,
+    schedule: u32,
     angle_setpoint: i32,
     current: i32,
     rotate_speed: i32,
+    rotate_angle: i32,
+    rotate_stopping: bool,
     control_type: ControlType,
     enabled: bool,
     //pid: PIDController<i32>,
@@ -32,22 +90,63 @@ enum ControlType {
     Position,
     Hold,
     Calibration,
+    Homing,
+    Stopping,
 }
 
-impl<T1, T2, Inp> MotorControl<T1, T2, Inp>
+impl<T1, T2, Inp, Sw, const N: usize, Tables> MotorControl<T1, T2, Inp, Sw, N, Tables>
 where
     T1: CurrentDevice + PIDControl,
     T2: CurrentDevice + PIDControl,
     Inp: PositionInput,
+    Sw: SwitchInput,
+    Tables: TableStorage<N>,
 {
-    pub fn new(output_coil_a: T1, output_coil_b: T2, position_input: Inp) -> Self {
+    /// `tables` live in a `static` on the drive, see `PositionTables`.
+    pub fn new(
+        output_coil_a: T1,
+        output_coil_b: T2,
+        position_input: Inp,
+        switches: Sw,
+        motor_config: MotorConfig,
+        encoder_config: EncoderConfig,
+        tables: Tables,
+    ) -> Self {
         Self {
             coil_a: Coil::<T1>::new(output_coil_a),
             coil_b: Coil::<T2>::new(output_coil_b),
-            position_control: PositionControl::<Inp>::new(position_input, UPDATE_PERIOD),
+            position_control: PositionControl::new(
+                position_input,
+                UPDATE_FREQUENCY,
+                motor_config,
+                encoder_config,
+                tables,
+            ),
+            switches,
+            homing: Homing::new(HomingConfig::default()),
+            soft_limits: SoftLimits::default(),
+            limit_violation: None,
+            limit_reported: false,
+            jog_velocity: None,
+            stop_config: StopConfig::default(),
+            stop_ramp: VelocityRamp::new(StopConfig::default().deceleration, UPDATE_FREQUENCY),
+            estop_category: StopCategory::Immediate,
+            estop_latched: false,
+            sense_fault: false,
+            stop_disables: false,
+            stop_hold_updates: 0,
+            current_percent: 100,
+            idle_current: IdleCurrent::new(IdleConfig::default()),
+            adaptive_current: None,
+            idle_setpoint: MultiTurnPosition::ZERO,
+            stall_detector: StallDetector::new(StallConfig::default()),
+            waveform: Waveform::default(),
+            schedule: UPDATE_PERIOD as u32,
             angle_setpoint: 0,
             current: 0,
             rotate_speed: 10,
+            rotate_angle: 0,
+            rotate_stopping: false,
             control_type: ControlType::Hold,
             enabled: false,
             //pid: PIDController::new(0, 0, 0),
@@ -55,45 +154,62 @@ where
     }
     // Returns next requested schedule in cycles
     pub fn update(&mut self) -> u32 {
+        self.position_control
+            .update_velocity(DWT_FREQ / self.schedule.max(1) as i32);
+        self.check_estop();
+        self.check_stall();
+        self.check_sense();
+        self.update_idle_current();
+
+        self.schedule = self.update_control();
+        self.schedule
+    }
+    /// Reduce the current when standing still in Hold or Position, the
+    /// standstill time does not count while disabled.
+    fn update_idle_current(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let elapsed_us = self.schedule / (DWT_FREQ / 1_000_000) as u32;
+        let position = self.position_control.get_current_position();
+        let setpoint_changed = match self.control_type {
+            ControlType::Hold | ControlType::Position => {
+                let setpoint = self.position_control.get_position_setpoint();
+                let changed = setpoint != self.idle_setpoint;
+                self.idle_setpoint = setpoint;
+                changed
+            }
+            _ => true,
+        };
+        self.idle_current
+            .update(elapsed_us, position, setpoint_changed);
+    }
+    fn update_control(&mut self) -> u32 {
         if !self.enabled {
             return DWT_FREQ as u32 / 100;
         }
 
         match self.control_type {
-            ControlType::Rotate => {
-                static mut DEGREES: i32 = 0;
-                unsafe {
-                    if self.rotate_speed >= 0 {
-                        DEGREES = if DEGREES < 360 { DEGREES + 1 } else { 0 };
-                    } else {
-                        DEGREES = if DEGREES > 0 { DEGREES - 1 } else { 360 };
-                    }
-
-                    self.set_angle(DEGREES);
-                };
-
-                // Request next update in..
-                let rotate_speed = if self.rotate_speed >= 0 {
-                    self.rotate_speed as u32
-                } else {
-                    (-1 * self.rotate_speed) as u32
-                };
-
-                if rotate_speed != 0 {
-                    200_000 / rotate_speed
-                } else {
-                    200_000
-                }
-            }
+            ControlType::Rotate if self.rotate_stopping => self.update_rotate_stop(),
+            ControlType::Rotate => self.update_rotate(),
             ControlType::Hold => {
-                self.coil_a.current_control().set_current(self.current);
-                self.coil_b.current_control().set_current(self.current);
+                let current = self.drive_current();
+                self.coil_a.current_control().set_current(current);
+                self.coil_b.current_control().set_current(current);
                 200_000
             }
             ControlType::Position => {
-                self.position_control.update();
-                let angle = self.position_control.angle();
-                self.set_angle(angle);
+                self.stop_at_limit_switch();
+                if let Some(velocity) = self.jog_velocity {
+                    // Slow down and stop at the soft limits.
+                    let setpoint = self.position_control.get_position_setpoint();
+                    let limited_velocity = self.soft_limits.limit_velocity(setpoint, velocity);
+                    if limited_velocity == 0 && velocity != 0 {
+                        self.report_limit(Side::from_sign(velocity));
+                    }
+                    self.position_control.set_velocity(limited_velocity);
+                }
+                self.follow_setpoint();
 
                 UPDATE_PERIOD as u32
             }
@@ -101,15 +217,319 @@ where
                 self.position_control.update();
 
                 if self.position_control.calibration_is_done() {
-                    self.enable(false);
+                    self.disable_now();
                     self.control_type = ControlType::Hold;
                 } else {
-                    let angle = self.position_control.angle();
-                    self.set_angle(angle);
+                    let angle = self.position_control.electrical_angle();
+                    self.set_electrical_angle(angle);
                 }
 
                 UPDATE_PERIOD as u32
             }
+            ControlType::Homing => {
+                self.update_homing();
+                self.follow_setpoint();
+
+                UPDATE_PERIOD as u32
+            }
+            ControlType::Stopping => {
+                if !self.stop_ramp.is_stopped() {
+                    let velocity = self.stop_ramp.update();
+                    self.position_control.set_velocity(velocity);
+                } else if self.stop_hold_updates > 0 {
+                    // Keep the position at full current.
+                    self.stop_hold_updates -= 1;
+                } else {
+                    self.finish_stop();
+                    return UPDATE_PERIOD as u32;
+                }
+                self.follow_setpoint();
+
+                UPDATE_PERIOD as u32
+            }
+        }
+    }
+    /// Step the open loop angle, returns the time to the next step.
+    fn update_rotate(&mut self) -> u32 {
+        // Do not move into an active limit switch.
+        if self.rotate_speed != 0
+            && self
+                .switches
+                .limit_active(Side::from_sign(self.rotate_speed))
+        {
+            return 200_000;
+        }
+
+        // Slow down and stop at the soft limits, which work in pulses.
+        let position = self.position_control.get_current_position();
+        let velocity = self.position_control.cycles_to_pulses(self.rotate_speed);
+        let limited = self.soft_limits.limit_velocity(position, velocity);
+        if limited == 0 && self.rotate_speed != 0 {
+            self.report_limit(Side::from_sign(self.rotate_speed));
+            return 200_000;
+        }
+        let rotate_speed = if limited == velocity {
+            self.rotate_speed
+        } else {
+            // Keep creeping below one electrical cycle per second.
+            match self.position_control.pulses_to_cycles(limited) {
+                0 => limited.signum(),
+                speed => speed,
+            }
+        };
+
+        let degrees = self.rotate_angle;
+        self.rotate_angle = if rotate_speed >= 0 {
+            if degrees < 360 {
+                degrees + 1
+            } else {
+                0
+            }
+        } else if degrees > 0 {
+            degrees - 1
+        } else {
+            360
+        };
+        self.set_angle(self.rotate_angle);
+
+        // Request next update in..
+        200_000_u32
+            .checked_div(rotate_speed.unsigned_abs())
+            .unwrap_or(200_000)
+    }
+    /// Ramp the open loop speed down, there is no position to follow.
+    fn update_rotate_stop(&mut self) -> u32 {
+        if !self.stop_ramp.is_stopped() {
+            let period = self.update_rotate();
+            self.rotate_speed = self.stop_ramp.update_by(period);
+            period
+        } else if self.stop_hold_updates > 0 {
+            // Keep the angle at full current.
+            self.stop_hold_updates -= 1;
+            UPDATE_PERIOD as u32
+        } else {
+            match self.stop_config.after_stop {
+                AfterStop::Hold { current_percent } if !self.stop_disables => {
+                    self.current_percent = current_percent;
+                    self.set_angle(self.rotate_angle);
+                    200_000
+                }
+                _ => {
+                    self.disable_now();
+                    UPDATE_PERIOD as u32
+                }
+            }
+        }
+    }
+    fn follow_setpoint(&mut self) {
+        self.position_control
+            .set_drive_current(self.drive_current());
+        self.position_control.update();
+        if let Some(adaptive_current) = &mut self.adaptive_current {
+            adaptive_current.update(self.position_control.load());
+        }
+        let angle = self.position_control.electrical_angle();
+        self.set_electrical_angle(angle);
+
+        // Feed forward against the detent torque.
+        let (angle, current) = self.position_control.cogging_current();
+        if current != 0 {
+            self.coil_a.add_current(angle, current, &self.waveform);
+            self.coil_b.add_current(
+                angle.wrapping_add(ElectricalAngle::QUARTER),
+                current,
+                &self.waveform,
+            );
+        }
+    }
+    fn check_estop(&mut self) {
+        if self.estop_latched {
+            // Only the controlled stop may keep the coils enabled.
+            if self.enabled && !matches!(self.control_type, ControlType::Stopping) {
+                self.disable_now();
+            }
+        } else if self.switches.estop_active() {
+            self.estop_latched = true;
+            match self.estop_category {
+                StopCategory::Immediate => self.disable_now(),
+                StopCategory::Controlled => self.controlled_stop(true),
+            }
+        }
+    }
+    /// Compare the rotor with the commanded angle, re-sync or fault on a
+    /// stall. Point to point moves are not checked, the setpoint jumps there.
+    fn check_stall(&mut self) {
+        if self.stall_detector.is_fault() {
+            if self.enabled {
+                self.disable_now();
+            }
+            return;
+        }
+
+        // Without a calibration the rotor angle is not known.
+        let lag = match self.control_type {
+            _ if !self.enabled || !self.position_control.calibration_is_done() => None,
+            ControlType::Rotate if self.rotate_speed != 0 => {
+                let lag = self.rotate_angle - self.position_control.detected_angle();
+                Some((lag + 180).rem_euclid(360) - 180)
+            }
+            ControlType::Position | ControlType::Homing | ControlType::Stopping
+                if self.position_control.is_velocity_driven() =>
+            {
+                Some(self.position_control.following_error_degrees())
+            }
+            _ => None,
+        };
+
+        match self.stall_detector.update(lag) {
+            Some(StallEvent::Stall) if self.stall_detector.config().resync => {
+                self.rotate_angle = self.position_control.detected_angle();
+                self.position_control.resync();
+            }
+            Some(StallEvent::Fault) => self.disable_now(),
+            _ => {}
+        }
+    }
+    /// Decelerate from the measured velocity to standstill and keep the
+    /// position for the hold time. Then disable, or continue with the
+    /// holding current when allowed by `disable`.
+    fn controlled_stop(&mut self, disable: bool) {
+        match self.control_type {
+            _ if !self.enabled => return,
+            ControlType::Hold | ControlType::Calibration => {
+                self.disable_now();
+                return;
+            }
+            ControlType::Stopping => {
+                self.stop_disables |= disable;
+                return;
+            }
+            ControlType::Rotate if self.rotate_stopping => {
+                self.stop_disables |= disable;
+                return;
+            }
+            _ => {}
+        }
+        self.stop_disables = disable;
+        self.stop_hold_updates =
+            (self.stop_config.hold_time_ms as u64 * UPDATE_FREQUENCY as u64 / 1000) as u32;
+
+        if let ControlType::Rotate = self.control_type {
+            // Open loop, ramp the speed down in electrical cycles per second.
+            self.stop_ramp = VelocityRamp::new(self.stop_config.rotate_deceleration, DWT_FREQ);
+            self.stop_ramp.start(self.rotate_speed);
+            self.rotate_stopping = true;
+            return;
+        }
+
+        let position = self.position_control.get_current_position();
+        self.position_control.set_position(position);
+        self.jog_velocity = None;
+
+        let velocity = self.position_control.measured_velocity();
+        self.stop_ramp = VelocityRamp::new(self.stop_config.deceleration, UPDATE_FREQUENCY);
+        self.stop_ramp.start(velocity);
+        self.position_control.set_velocity(velocity);
+        self.control_type = ControlType::Stopping;
+    }
+    fn finish_stop(&mut self) {
+        match self.stop_config.after_stop {
+            AfterStop::Hold { current_percent } if !self.stop_disables => {
+                self.current_percent = current_percent;
+                let position = self.position_control.get_current_position();
+                self.position_control.set_position(position);
+                self.control_type = ControlType::Position;
+            }
+            _ => self.disable_now(),
+        }
+    }
+    /// Start a controlled stop, see `StopConfig`.
+    pub fn quick_stop(&mut self) {
+        self.controlled_stop(false);
+    }
+    /// Stop a move towards an active limit switch, moving away is allowed.
+    fn stop_at_limit_switch(&mut self) {
+        let direction = match self.jog_velocity {
+            Some(velocity) => velocity as i64,
+            None => self.position_control.following_error(),
+        };
+        if direction != 0
+            && self
+                .switches
+                .limit_active(Side::from_sign(direction.signum() as i32))
+        {
+            self.jog_velocity = None;
+            let position = self.position_control.get_current_position();
+            self.position_control.set_position(position);
+        }
+    }
+    /// Latch a current sense fault of either coil, the coils stay off.
+    fn check_sense(&mut self) {
+        if self.coil_a.current_control().is_sense_fault()
+            || self.coil_b.current_control().is_sense_fault()
+        {
+            self.sense_fault = true;
+        }
+        if self.sense_fault && self.enabled {
+            self.disable_now();
+        }
+    }
+    /// Clear a latched sense fault. It is latched again when the zeroing on
+    /// the next enable still finds an implausible offset.
+    pub fn reset_sense_fault(&mut self) {
+        self.sense_fault = false;
+    }
+    /// Clear a latched emergency stop, fails while the E-stop is active.
+    pub fn reset_estop(&mut self) -> bool {
+        if self.switches.estop_active() {
+            return false;
+        }
+        self.estop_latched = false;
+        true
+    }
+    pub fn stall_detector(&mut self) -> &mut StallDetector {
+        &mut self.stall_detector
+    }
+    pub fn status(&self) -> Status {
+        Status {
+            enabled: self.enabled,
+            estop_latched: self.estop_latched,
+            stall_count: self.stall_detector.stall_count(),
+            stall_fault: self.stall_detector.is_fault(),
+            sense_fault: self.sense_fault,
+            position: self.position_control.get_current_position(),
+            following_error: self.position_control.following_error(),
+        }
+    }
+    pub fn is_estop_latched(&self) -> bool {
+        self.estop_latched
+    }
+    pub fn set_estop_category(&mut self, category: StopCategory) {
+        self.estop_category = category;
+    }
+    pub fn set_stop_config(&mut self, stop_config: StopConfig) {
+        self.stop_config = stop_config;
+    }
+    fn update_homing(&mut self) {
+        let input = HomingInput {
+            position: self.position_control.get_current_position(),
+            following_error: self.position_control.following_error(),
+            limit_active: self.switches.limit_active(self.homing.config().direction),
+            current_saturated: self.coil_a.current_control().is_saturated()
+                || self.coil_b.current_control().is_saturated(),
+        };
+
+        match self.homing.update(&input) {
+            Some(HomingAction::Jog(velocity)) => self.position_control.set_velocity(velocity),
+            Some(HomingAction::MoveTo(position)) => self.position_control.set_position(position),
+            Some(HomingAction::SetHome(home)) => self.position_control.set_home(home),
+            Some(HomingAction::Done) => self.control_type = ControlType::Position,
+            Some(HomingAction::Failed) => {
+                // Stay where we are.
+                self.position_control.set_position(input.position);
+                self.control_type = ControlType::Position;
+            }
+            None => {}
         }
     }
     pub fn update_control_loop(&mut self, dt: u32) {
@@ -122,36 +542,194 @@ where
     pub fn coil_b(&mut self) -> &mut Coil<T2> {
         &mut self.coil_b
     }
+    /// Disabling stops controlled first, when enabled in the `StopConfig`.
+    /// Enabling is refused while a fault is latched, see `is_fault_latched`.
     pub fn enable(&mut self, enable: bool) {
+        if enable {
+            if !self.is_fault_latched() {
+                self.set_outputs(true);
+                self.idle_current.restart();
+            }
+        } else if self.stop_config.controlled_disable {
+            self.controlled_stop(true);
+        } else {
+            self.disable_now();
+        }
+    }
+    /// Disable the coils right away, the motor can run out freely.
+    pub fn disable_now(&mut self) {
+        self.set_outputs(false);
+        self.control_type = ControlType::Hold;
+    }
+    fn set_outputs(&mut self, enable: bool) {
         self.coil_a.current_control().enable(enable);
         self.coil_b.current_control().enable(enable);
+        self.current_percent = 100;
         self.enabled = enable;
     }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    /// The E-stop, a stall or a sense fault keeps the coils off until reset.
+    pub fn is_fault_latched(&self) -> bool {
+        self.estop_latched || self.stall_detector.is_fault() || self.sense_fault
+    }
     pub fn set_current(&mut self, current: i32) {
         self.current = current;
+        self.idle_current.restart();
+    }
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
     }
-    pub fn set_position(&mut self, position: i32) {
+    pub fn waveform(&self) -> &Waveform {
+        &self.waveform
+    }
+    /// Change one point of the waveform table, a sine waveform becomes a
+    /// table first.
+    pub fn set_waveform_point(&mut self, index: usize, value: i32) {
+        if let Waveform::Sine = self.waveform {
+            self.waveform = Waveform::Table(WaveformTable::sine());
+        }
+        if let Waveform::Table(table) = &mut self.waveform {
+            table.set_point(index, value);
+        }
+    }
+    /// Use a waveform corrected with the harmonics found by the calibration,
+    /// fails without a calibration.
+    pub fn use_harmonic_waveform(&mut self) -> bool {
+        match self.position_control.harmonic_error() {
+            Some((sine, cosine)) => {
+                self.waveform = Waveform::Table(WaveformTable::harmonic_corrected(sine, cosine));
+                true
+            }
+            None => false,
+        }
+    }
+    /// Scale the current with the load while following the position, instead
+    /// of the fixed current. `None` for the fixed current.
+    pub fn set_adaptive_current(&mut self, config: Option<AdaptiveConfig>) {
+        self.adaptive_current = config.map(AdaptiveCurrent::new);
+    }
+    /// Move to `position`, when it is outside of the soft limits the move is
+    /// clamped or rejected depending on the `LimitMode`.
+    pub fn set_position(&mut self, position: MultiTurnPosition) -> Result<(), LimitViolation> {
+        match self.soft_limits.check_target(position) {
+            Ok(target) => {
+                self.move_to(target);
+                Ok(())
+            }
+            Err(violation) => {
+                if violation.clamped {
+                    self.move_to(self.soft_limits.limit(violation.side));
+                }
+                Err(violation)
+            }
+        }
+    }
+    fn move_to(&mut self, position: MultiTurnPosition) {
+        self.current_percent = 100;
+        self.jog_velocity = None;
+        self.limit_reported = false;
+        self.idle_current.restart();
         self.position_control.set_position(position);
         self.control_type = ControlType::Position;
     }
+    /// Move with `velocity` pulses per second, until stopped by another
+    /// command or a soft limit.
+    pub fn jog(&mut self, velocity: i32) {
+        let position = self.position_control.get_current_position();
+        self.move_to(position);
+        self.jog_velocity = Some(velocity);
+        self.position_control.set_velocity(velocity);
+    }
+    pub fn set_soft_limits(&mut self, soft_limits: SoftLimits) {
+        self.soft_limits = soft_limits;
+    }
+    pub fn soft_limits(&self) -> &SoftLimits {
+        &self.soft_limits
+    }
+    /// The soft limit stop of a rotate or jog, reported once per move.
+    pub fn take_limit_violation(&mut self) -> Option<LimitViolation> {
+        self.limit_violation.take()
+    }
+    fn report_limit(&mut self, side: Side) {
+        if !self.limit_reported {
+            self.limit_reported = true;
+            self.limit_violation = Some(LimitViolation {
+                side,
+                target: self.position_control.get_current_position(),
+                clamped: true,
+            });
+        }
+    }
+    /// Measure the detent torque over one rotation with `velocity` pulses
+    /// per second, needs a calibration. See `CoggingTable`.
+    pub fn measure_cogging(&mut self, velocity: i32) {
+        let position = self.position_control.get_current_position();
+        self.move_to(position);
+        self.position_control.start_cogging_measurement(velocity);
+    }
+    /// Find the encoder index pulse with `speed` pulses per second, the
+    /// index becomes the zero position.
+    pub fn home_to_index(&mut self, speed: i32) {
+        self.position_control.start_index_homing(speed);
+        self.idle_current.restart();
+        self.control_type = ControlType::Position;
+    }
     pub fn set_speed(&mut self, speed: i32) {
         self.position_control.set_speed(speed);
+        self.idle_current.restart();
         self.control_type = ControlType::Position;
     }
-    pub fn position_control(&mut self) -> &mut PositionControl<Inp> {
+    /// Home with the configured method, see `homing()`.
+    pub fn home(&mut self, direction: Side, speed: i32, offset: i64) {
+        let config = HomingConfig {
+            direction,
+            search_speed: speed,
+            offset,
+            ..*self.homing.config()
+        };
+        self.homing.set_config(config);
+
+        let position = self.position_control.get_current_position();
+        self.position_control.set_position(position);
+        if let HomingAction::Jog(velocity) = self.homing.start(position) {
+            self.position_control.set_velocity(velocity);
+        }
+        self.idle_current.restart();
+        self.control_type = ControlType::Homing;
+    }
+    pub fn homing(&mut self) -> &mut Homing {
+        &mut self.homing
+    }
+    pub fn idle_current(&mut self) -> &mut IdleCurrent {
+        &mut self.idle_current
+    }
+    pub fn switches(&mut self) -> &mut Sw {
+        &mut self.switches
+    }
+    pub fn position_control(&mut self) -> &mut PositionControl<Inp, N, Tables> {
         &mut self.position_control
     }
     pub fn handle_new_position(&mut self) {
         self.position_control.update_position();
     }
     pub fn rotate(&mut self, speed: i32) {
+        self.current_percent = 100;
+        self.limit_reported = false;
         self.rotate_speed = speed;
+        self.rotate_stopping = false;
+        self.idle_current.restart();
         self.control_type = ControlType::Rotate;
     }
     pub fn hold(&mut self) {
+        self.idle_current.restart();
         self.control_type = ControlType::Hold;
     }
     pub fn calibrate(&mut self) {
+        if self.is_fault_latched() {
+            return;
+        }
         self.control_type = ControlType::Calibration;
         self.position_control.start_calibration();
         self.enable(true);
@@ -162,25 +740,59 @@ where
     }
 }
 
-impl<T1, T2, Inp> PositionControlled for MotorControl<T1, T2, Inp>
+impl<T1, T2, Inp, Sw, const N: usize, Tables> MotorControl<T1, T2, Inp, Sw, N, Tables>
+where
+    T1: CurrentDevice,
+    T2: CurrentDevice,
+    Tables: TableStorage<N>,
+{
+    /// Current for the coils, scaled with the load when following the
+    /// position, reduced while holding after a stop and at standstill.
+    fn drive_current(&self) -> i32 {
+        let current = match (&self.adaptive_current, &self.control_type) {
+            (
+                Some(adaptive_current),
+                ControlType::Position | ControlType::Homing | ControlType::Stopping,
+            ) => adaptive_current.current(),
+            _ => self.current,
+        };
+        self.idle_current
+            .scale(current * self.current_percent / 100)
+    }
+}
+
+impl<T1, T2, Inp, Sw, const N: usize, Tables> PositionControlled
+    for MotorControl<T1, T2, Inp, Sw, N, Tables>
 where
     T1: CurrentDevice,
     T2: CurrentDevice,
+    Tables: TableStorage<N>,
 {
     fn set_angle(&mut self, degrees: i32) {
+        self.set_electrical_angle(ElectricalAngle::from_degrees(degrees));
         self.angle_setpoint = degrees;
-        self.coil_a.set_angle(degrees, self.current);
-        self.coil_b.set_angle(degrees + 90, self.current);
+    }
+    fn set_electrical_angle(&mut self, angle: ElectricalAngle) {
+        self.angle_setpoint = angle.degrees();
+        let current = self.drive_current();
+        self.coil_a.set_angle(angle, current, &self.waveform);
+        self.coil_b.set_angle(
+            angle.wrapping_add(ElectricalAngle::QUARTER),
+            current,
+            &self.waveform,
+        );
     }
     fn get_angle(&self) -> i32 {
         self.angle_setpoint
     }
 }
 
-impl<T1, T2, Inp> PIDControl for MotorControl<T1, T2, Inp>
+impl<T1, T2, Inp, Sw, const N: usize, Tables> PIDControl
+    for MotorControl<T1, T2, Inp, Sw, N, Tables>
 where
     T1: CurrentDevice + PIDControl,
     T2: CurrentDevice + PIDControl,
+    Tables: TableStorage<N>,
 {
     fn set_controller_p(&mut self, value: i32) {
         self.coil_a.current_control().set_controller_p(value);
@@ -200,8 +812,491 @@ where
 // Tests
 //
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position_control::Direction;
+    use crate::soft_limits::LimitMode;
+
+    #[derive(Default)]
+    struct MockCurrentDevice {
+        current: i32,
+        enabled: bool,
+        sense_fault: bool,
+    }
+    impl CurrentDevice for MockCurrentDevice {
+        fn update(&mut self, _dt: u32) {}
+        fn set_current(&mut self, milli_amps: i32) {
+            self.current = milli_amps;
+        }
+        fn current(&self) -> i32 {
+            self.current
+        }
+        fn enable(&mut self, enable: bool) {
+            self.enabled = enable;
+        }
+        fn force_duty(&mut self, _duty: i32) {}
+        fn is_sense_fault(&self) -> bool {
+            self.sense_fault
+        }
+    }
+    impl PIDControl for MockCurrentDevice {
+        fn set_controller_p(&mut self, _value: i32) {}
+        fn set_controller_i(&mut self, _value: i32) {}
+        fn set_controller_d(&mut self, _value: i32) {}
+    }
+
+    struct DummyInput {
+        position: i32,
+    }
+    impl PositionInput for DummyInput {
+        fn update(&mut self) {}
+        fn reset(&mut self) {
+            self.position = 0;
+        }
+        fn get_position(&self) -> i32 {
+            self.position
+        }
+        fn get_direction(&self) -> Direction {
+            Direction::Unknown(0)
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSwitches {
+        negative: bool,
+        positive: bool,
+        estop: bool,
+    }
+    impl SwitchInput for MockSwitches {
+        fn limit_active(&self, side: Side) -> bool {
+            match side {
+                Side::Negative => self.negative,
+                Side::Positive => self.positive,
+            }
+        }
+        fn estop_active(&self) -> bool {
+            self.estop
+        }
+    }
+
+    type TestMotorControl = MotorControl<
+        MockCurrentDevice,
+        MockCurrentDevice,
+        DummyInput,
+        MockSwitches,
+        DEFAULT_TABLE_SIZE,
+        Box<PositionTables>,
+    >;
+
+    fn motor_control() -> TestMotorControl {
+        let mut motor_control = MotorControl::new(
+            MockCurrentDevice::default(),
+            MockCurrentDevice::default(),
+            DummyInput { position: 0 },
+            MockSwitches::default(),
+            MotorConfig::default(),
+            EncoderConfig::default(),
+            Box::new(PositionTables::new()),
+        );
+        motor_control.set_current(100);
+        motor_control.enable(true);
+        motor_control
+    }
+
+    #[test]
+    fn tables_in_static_storage() {
+        type StaticMotorControl =
+            MotorControl<MockCurrentDevice, MockCurrentDevice, DummyInput, MockSwitches>;
+        static mut TABLES: PositionTables = PositionTables::new();
+        let tables = unsafe { &mut *core::ptr::addr_of_mut!(TABLES) };
+        let mut motor_control: StaticMotorControl = MotorControl::new(
+            MockCurrentDevice::default(),
+            MockCurrentDevice::default(),
+            DummyInput { position: 0 },
+            MockSwitches::default(),
+            MotorConfig::default(),
+            EncoderConfig::default(),
+            tables,
+        );
+        motor_control.calibrate();
+        motor_control.update();
+
+        // The drive itself fits on the stack, the tables are not in it.
+        assert!(core::mem::size_of::<StaticMotorControl>() < 2048);
+    }
+
+    #[test]
+    fn estop_latches() {
+        let mut motor_control = motor_control();
+        motor_control.switches().estop = true;
+        motor_control.update();
+        assert!(!motor_control.is_enabled());
+        assert!(motor_control.is_estop_latched());
+
+        // Can not be enabled while latched.
+        motor_control.switches().estop = false;
+        motor_control.enable(true);
+        motor_control.update();
+        assert!(!motor_control.is_enabled());
+
+        assert!(motor_control.reset_estop());
+        motor_control.enable(true);
+        motor_control.update();
+        assert!(motor_control.is_enabled());
+    }
+
+    #[test]
+    fn latched_estop_keeps_outputs_off() {
+        let mut motor_control = motor_control();
+        motor_control.switches().estop = true;
+        motor_control.update();
+        motor_control.switches().estop = false;
+
+        // No power on the coils, not even until the next update.
+        motor_control.enable(true);
+        assert!(!motor_control.is_enabled());
+        assert!(!motor_control.coil_a().current_control().enabled);
+        assert!(!motor_control.coil_b().current_control().enabled);
+        motor_control.calibrate();
+        assert!(!motor_control.is_enabled());
+        assert!(!motor_control.coil_a().current_control().enabled);
+        assert!(matches!(motor_control.control_type, ControlType::Hold));
+    }
+
+    #[test]
+    fn estop_reset_needs_released_input() {
+        let mut motor_control = motor_control();
+        motor_control.switches().estop = true;
+        motor_control.update();
+        assert!(!motor_control.reset_estop());
+        assert!(motor_control.is_estop_latched());
+    }
+
+    #[test]
+    fn jog_reaches_soft_limit() {
+        let mut motor_control = motor_control();
+        motor_control.set_soft_limits(SoftLimits {
+            deceleration: 20_000,
+            ..SoftLimits::new((-100).into(), 100.into(), LimitMode::Reject)
+        });
+        motor_control.jog(4000);
+        for _ in 0..UPDATE_FREQUENCY {
+            motor_control.update();
+        }
+        assert_eq!(
+            MultiTurnPosition::from(100),
+            motor_control.position_control().get_position_setpoint()
+        );
+    }
 
-// }
+    #[test]
+    fn controlled_estop_ramps_down() {
+        let mut motor_control = motor_control();
+        motor_control.set_estop_category(StopCategory::Controlled);
+        motor_control.set_stop_config(StopConfig {
+            hold_time_ms: 1,
+            ..StopConfig::default()
+        });
+        motor_control.jog(0);
+        motor_control.switches().estop = true;
+        motor_control.update();
+        assert!(motor_control.is_estop_latched());
+        assert!(motor_control.is_enabled());
+
+        // Standing still, so only the hold time is left.
+        for _ in 0..UPDATE_FREQUENCY / 1000 {
+            motor_control.update();
+        }
+        assert!(!motor_control.is_enabled());
+    }
+
+    #[test]
+    fn disable_holds_before_disabling() {
+        let mut motor_control = motor_control();
+        motor_control.set_stop_config(StopConfig {
+            hold_time_ms: 1,
+            ..StopConfig::default()
+        });
+        motor_control.jog(0);
+        motor_control.update();
+
+        motor_control.enable(false);
+        let hold_updates = UPDATE_FREQUENCY / 1000;
+        for _ in 0..hold_updates {
+            motor_control.update();
+            assert!(motor_control.is_enabled());
+        }
+        motor_control.update();
+        assert!(!motor_control.is_enabled());
+
+        // Or right away.
+        motor_control.enable(true);
+        motor_control.jog(0);
+        motor_control.disable_now();
+        assert!(!motor_control.is_enabled());
+    }
+
+    #[test]
+    fn quick_stop_keeps_holding_current() {
+        let mut motor_control = motor_control();
+        motor_control.set_stop_config(StopConfig {
+            hold_time_ms: 0,
+            after_stop: AfterStop::Hold {
+                current_percent: 50,
+            },
+            ..StopConfig::default()
+        });
+        motor_control.jog(0);
+        motor_control.update();
+
+        motor_control.quick_stop();
+        motor_control.update();
+        motor_control.update();
+        assert!(motor_control.is_enabled());
+        assert_eq!(50, motor_control.drive_current());
+
+        // Full current again on the next move.
+        motor_control.set_position(10.into()).unwrap();
+        assert_eq!(100, motor_control.drive_current());
+    }
+
+    #[test]
+    fn stop_from_rotate_stays_open_loop() {
+        let mut motor_control = motor_control();
+        motor_control.set_stop_config(StopConfig {
+            rotate_deceleration: 1000,
+            hold_time_ms: 1,
+            ..StopConfig::default()
+        });
+        motor_control.rotate(100);
+        motor_control.update();
+
+        motor_control.quick_stop();
+        let mut elapsed = 0_u64;
+        let mut speed = motor_control.rotate_speed;
+        while motor_control.rotate_speed != 0 {
+            elapsed += motor_control.update() as u64;
+            assert!(matches!(motor_control.control_type, ControlType::Rotate));
+            assert!(motor_control.rotate_speed <= speed);
+            speed = motor_control.rotate_speed;
+        }
+        // About 100 ms from 100 cycles per second at 1000 per second squared,
+        // the last steps are slow.
+        let elapsed_ms = elapsed * 1000 / DWT_FREQ as u64;
+        assert!((100..105).contains(&elapsed_ms), "{}", elapsed_ms);
+
+        for _ in 0..UPDATE_FREQUENCY / 1000 {
+            motor_control.update();
+            assert!(motor_control.is_enabled());
+        }
+        motor_control.update();
+        assert!(!motor_control.is_enabled());
+    }
+
+    #[test]
+    fn idle_current_at_standstill() {
+        let mut motor_control = motor_control();
+        motor_control.idle_current().set_config(IdleConfig {
+            standstill_ms: 1,
+            ramp_down_ms: 0,
+            ..IdleConfig::default()
+        });
+        motor_control.update();
+        motor_control.update();
+        assert_eq!(50, motor_control.coil_a().current_control().current);
+
+        // Back to full current on a new setpoint.
+        motor_control
+            .idle_current()
+            .set_config(IdleConfig::default());
+        motor_control.set_position(100.into()).unwrap();
+        motor_control.update();
+        assert_eq!(100, motor_control.drive_current());
+    }
+
+    #[test]
+    fn idle_current_restarts_on_any_command() {
+        let mut motor_control = motor_control();
+        // Longer than the update period in Hold.
+        motor_control.idle_current().set_config(IdleConfig {
+            standstill_ms: 20,
+            ramp_down_ms: 0,
+            ramp_up_ms: 0,
+            ..IdleConfig::default()
+        });
+        let idle = |motor_control: &mut TestMotorControl| {
+            for _ in 0..UPDATE_FREQUENCY / 10 {
+                motor_control.update();
+            }
+            assert_eq!(50, motor_control.drive_current());
+        };
+
+        // Also without a new setpoint.
+        idle(&mut motor_control);
+        motor_control.hold();
+        motor_control.update();
+        assert_eq!(100, motor_control.drive_current());
+
+        idle(&mut motor_control);
+        motor_control.set_current(100);
+        motor_control.update();
+        assert_eq!(100, motor_control.drive_current());
+
+        idle(&mut motor_control);
+        motor_control.set_position(0.into()).unwrap();
+        motor_control.update();
+        assert_eq!(100, motor_control.drive_current());
+
+        // The time disabled is no standstill.
+        motor_control.disable_now();
+        for _ in 0..UPDATE_FREQUENCY {
+            motor_control.update();
+        }
+        motor_control.enable(true);
+        motor_control.update();
+        assert_eq!(100, motor_control.drive_current());
+    }
+
+    #[test]
+    fn adaptive_current_with_load() {
+        let mut motor_control = motor_control();
+        motor_control.set_adaptive_current(Some(AdaptiveConfig {
+            min_current: 20,
+            max_current: 80,
+            decay: 1,
+        }));
+        motor_control.set_position(0.into()).unwrap();
+        motor_control.update();
+        assert_eq!(20, motor_control.drive_current());
+
+        // Far from the setpoint, full load.
+        motor_control.set_position(1000.into()).unwrap();
+        motor_control.update();
+        assert_eq!(80, motor_control.drive_current());
+
+        // Not in Hold.
+        motor_control.hold();
+        assert_eq!(100, motor_control.drive_current());
+    }
+
+    #[test]
+    fn uncalibrated_rotate_does_not_stall() {
+        let mut motor_control = motor_control();
+        motor_control.rotate(100);
+        for _ in 0..UPDATE_FREQUENCY {
+            motor_control.update();
+        }
+        let status = motor_control.status();
+        assert_eq!(0, status.stall_count);
+        assert!(!status.stall_fault);
+        assert!(status.enabled);
+    }
+
+    #[test]
+    fn stall_resyncs_then_faults() {
+        let mut motor_control = motor_control();
+        // The rotor does not move, all angles are recorded at 0.
+        motor_control.calibrate();
+        while !motor_control.position_control().calibration_is_done() {
+            motor_control.handle_new_position();
+            motor_control.update();
+        }
+        motor_control.enable(true);
+        motor_control.stall_detector().set_config(StallConfig {
+            max_lag: 90,
+            max_stalls: 2,
+            clear_updates: 100,
+            ..StallConfig::default()
+        });
+
+        // The rotor does not follow the open loop angle.
+        motor_control.rotate(1);
+        for _ in 0..91 {
+            motor_control.update();
+        }
+        assert_eq!(0, motor_control.status().stall_count);
+        motor_control.update();
+        assert_eq!(1, motor_control.status().stall_count);
+        // Re-synced to the rotor, one step further.
+        assert_eq!(1, motor_control.get_angle());
+
+        for _ in 0..92 {
+            motor_control.update();
+        }
+        let status = motor_control.status();
+        assert_eq!(2, status.stall_count);
+        assert!(status.stall_fault);
+        assert!(!status.enabled);
+
+        // Latched, the coils stay off right away.
+        motor_control.enable(true);
+        assert!(!motor_control.is_enabled());
+        assert!(!motor_control.coil_a().current_control().enabled);
+        motor_control.update();
+        assert!(!motor_control.is_enabled());
+        motor_control.stall_detector().reset_fault();
+        motor_control.enable(true);
+        motor_control.update();
+        assert!(motor_control.is_enabled());
+    }
+
+    #[test]
+    fn sense_fault_is_latched() {
+        let mut motor_control = motor_control();
+        motor_control.coil_b().current_control().sense_fault = true;
+        motor_control.update();
+        assert!(motor_control.status().sense_fault);
+        assert!(!motor_control.is_enabled());
+
+        // Latched, also when the coil measured a plausible offset again.
+        motor_control.coil_b().current_control().sense_fault = false;
+        motor_control.enable(true);
+        assert!(!motor_control.is_enabled());
+        assert!(!motor_control.coil_b().current_control().enabled);
+        motor_control.reset_sense_fault();
+        motor_control.enable(true);
+        motor_control.update();
+        assert!(motor_control.is_enabled());
+        assert!(!motor_control.status().sense_fault);
+    }
+
+    #[test]
+    fn stall_lag_wraps_around() {
+        let mut motor_control = motor_control();
+        motor_control.rotate(-1);
+        for _ in 0..10 {
+            motor_control.update();
+        }
+        assert_eq!(0, motor_control.status().stall_count);
+    }
+
+    #[test]
+    fn waveform_selection() {
+        let mut motor_control = motor_control();
+        assert!(!motor_control.use_harmonic_waveform());
+        assert_eq!(&Waveform::Sine, motor_control.waveform());
+
+        // Full current at 45 degrees instead of the sine.
+        motor_control.set_waveform_point(32, 65535);
+        motor_control.set_angle(45);
+        assert_eq!(100, motor_control.coil_a().current_control().current);
+        motor_control.set_waveform(Waveform::Sine);
+        motor_control.set_angle(45);
+        assert_eq!(70, motor_control.coil_a().current_control().current);
+    }
+
+    #[test]
+    fn limit_switch_blocks_own_direction() {
+        let mut motor_control = motor_control();
+        motor_control.switches().positive = true;
+
+        motor_control.rotate(10);
+        motor_control.update();
+        assert_eq!(0, motor_control.get_angle());
+
+        motor_control.rotate(-10);
+        motor_control.update();
+        assert_ne!(0, motor_control.get_angle());
+    }
+}
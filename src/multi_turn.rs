@@ -0,0 +1,108 @@
+use core::convert::TryFrom;
+
+/// Position over multiple turns, accumulated in encoder pulses.
+///
+/// The turns and the pulses within the turn depend on the encoder
+/// resolution, so these are derived on request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MultiTurnPosition(i64);
+
+impl MultiTurnPosition {
+    pub const ZERO: Self = Self(0);
+    pub const MIN: Self = Self(i64::MIN);
+    pub const MAX: Self = Self(i64::MAX);
+
+    pub const fn from_pulses(pulses: i64) -> Self {
+        Self(pulses)
+    }
+
+    /// A negative position is `turns` below zero plus `pulses` forward, so
+    /// -1 turn + 100 pulses is 100 pulses after the start of turn -1.
+    pub fn from_turns(turns: i32, pulses: u32, pulses_per_rotation: u32) -> Self {
+        Self(turns as i64 * pulses_per_rotation as i64 + pulses as i64)
+    }
+
+    pub fn pulses(self) -> i64 {
+        self.0
+    }
+
+    /// Whole turns, rounded towards negative infinity.
+    pub fn turns(self, pulses_per_rotation: u32) -> i32 {
+        let turns = self.0.div_euclid(pulses_per_rotation as i64);
+        i32::try_from(turns).unwrap_or(if turns < 0 { i32::MIN } else { i32::MAX })
+    }
+
+    /// Pulses within the current turn, always positive.
+    pub fn pulses_in_turn(self, pulses_per_rotation: u32) -> u32 {
+        self.0.rem_euclid(pulses_per_rotation as i64) as u32
+    }
+
+    pub fn checked_add(self, pulses: i64) -> Option<Self> {
+        self.0.checked_add(pulses).map(Self)
+    }
+
+    pub fn saturating_add(self, pulses: i64) -> Self {
+        Self(self.0.saturating_add(pulses))
+    }
+
+    /// Pulses from `other` to `self`.
+    pub fn checked_distance(self, other: Self) -> Option<i64> {
+        self.0.checked_sub(other.0)
+    }
+
+    /// Pulses from `other` to `self`, saturated at the i64 limits.
+    pub fn saturating_distance(self, other: Self) -> i64 {
+        self.0.saturating_sub(other.0)
+    }
+}
+
+impl From<i32> for MultiTurnPosition {
+    fn from(pulses: i32) -> Self {
+        Self(pulses as i64)
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PULSES_PER_ROTATION: u32 = 2400;
+
+    #[test]
+    fn turns_and_pulses() {
+        let position = MultiTurnPosition::from_turns(3, 100, PULSES_PER_ROTATION);
+        assert_eq!(3 * 2400 + 100, position.pulses());
+        assert_eq!(3, position.turns(PULSES_PER_ROTATION));
+        assert_eq!(100, position.pulses_in_turn(PULSES_PER_ROTATION));
+    }
+
+    #[test]
+    fn negative_position() {
+        let position = MultiTurnPosition::from_pulses(-1);
+        assert_eq!(-1, position.turns(PULSES_PER_ROTATION));
+        assert_eq!(2399, position.pulses_in_turn(PULSES_PER_ROTATION));
+
+        let position = MultiTurnPosition::from_pulses(-2400);
+        assert_eq!(-1, position.turns(PULSES_PER_ROTATION));
+        assert_eq!(0, position.pulses_in_turn(PULSES_PER_ROTATION));
+    }
+
+    #[test]
+    fn overflow_safe() {
+        assert_eq!(None, MultiTurnPosition::MAX.checked_add(1));
+        assert_eq!(
+            MultiTurnPosition::MAX,
+            MultiTurnPosition::MAX.saturating_add(1)
+        );
+        assert_eq!(
+            i64::MAX,
+            MultiTurnPosition::MAX.saturating_distance(MultiTurnPosition::MIN)
+        );
+        assert_eq!(i32::MAX, MultiTurnPosition::MAX.turns(1));
+        assert_eq!(i32::MIN, MultiTurnPosition::MIN.turns(1));
+    }
+}
@@ -115,9 +115,9 @@ where
     /// Creates a new PID Controller.
     pub fn new(p_gain: T, i_gain: T, d_gain: T) -> PIDController<T> {
         PIDController {
-            p_gain: p_gain,
-            i_gain: i_gain,
-            d_gain: d_gain,
+            p_gain,
+            i_gain,
+            d_gain,
             target: T::zero(),
 
             err_sum: T::zero(),
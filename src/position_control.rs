@@ -1,6 +1,16 @@
-use crate::calibration::{Calibration, DebugCalibrationData};
+use crate::calibration::{
+    Calibration, DebugCalibrationData, ANGLE_FRACTION_BITS, ANGLE_SCALE, DEFAULT_TABLE_SIZE,
+};
+use crate::cogging::CoggingTable;
+use crate::config::{EncoderConfig, MotorConfig};
+use crate::multi_turn::MultiTurnPosition;
+use crate::sine_lookup::angle::ElectricalAngle;
+use crate::sine_lookup::lookup;
+use crate::util;
+use core::borrow::BorrowMut;
 
-const PULSES_PER_ROTATION: usize = 600 * 4;
+const COIL_MAX_PULL_ANGLE: i32 = 90;
+const VELOCITY_FILTER: i64 = 4;
 
 #[derive(Clone, Copy)]
 pub enum Direction {
@@ -13,37 +23,149 @@ pub trait PositionInput {
     fn reset(&mut self);
     fn get_position(&self) -> i32;
     fn get_direction(&self) -> Direction;
+    /// An absolute input keeps its zero over a reset, so calibration does
+    /// not need to find it.
+    fn is_absolute(&self) -> bool {
+        false
+    }
+    /// Position (as `get_position`) of the index pulse seen since the last
+    /// call, if any.
+    fn take_index(&mut self) -> Option<i32> {
+        None
+    }
 }
 enum Mode {
     Normal,
+    Velocity,
     Calibration,
+    IndexHoming,
+}
+
+/// The tables per rotor position, calibration and cogging, 6 bytes per
+/// entry. Too big for the stack of a small MCU, build it with the const
+/// `new` in a `static` and hand it to `PositionControl::new`. On the host
+/// a `Box` owns it, see `TableStorage`.
+pub struct PositionTables<const N: usize = DEFAULT_TABLE_SIZE> {
+    calibration: DebugCalibrationData<N>,
+    cogging: [i16; N],
+}
+
+impl<const N: usize> PositionTables<N> {
+    pub const fn new() -> Self {
+        Self {
+            calibration: DebugCalibrationData::new(),
+            cogging: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> Default for PositionTables<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hands the `PositionTables` to the calibration and the cogging table.
+pub trait TableStorage<const N: usize> {
+    type Calibration: BorrowMut<DebugCalibrationData<N>>;
+    type Cogging: BorrowMut<[i16; N]>;
+    fn split(self) -> (Self::Calibration, Self::Cogging);
+}
+
+impl<'a, const N: usize> TableStorage<N> for &'a mut PositionTables<N> {
+    type Calibration = &'a mut DebugCalibrationData<N>;
+    type Cogging = &'a mut [i16; N];
+    fn split(self) -> (Self::Calibration, Self::Cogging) {
+        (&mut self.calibration, &mut self.cogging)
+    }
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl<const N: usize> TableStorage<N> for Box<PositionTables<N>> {
+    type Calibration = Box<DebugCalibrationData<N>>;
+    type Cogging = Box<[i16; N]>;
+    fn split(self) -> (Self::Calibration, Self::Cogging) {
+        let PositionTables {
+            calibration,
+            cogging,
+        } = *self;
+        (Box::new(calibration), Box::new(cogging))
+    }
 }
 
-pub struct PositionControl<Input> {
+pub struct PositionControl<
+    Input,
+    const N: usize = DEFAULT_TABLE_SIZE,
+    Tables = &'static mut PositionTables<N>,
+> where
+    Tables: TableStorage<N>,
+{
     mode: Mode,
-    calibration: Calibration,
-    //control_period: i32,
+    calibration: Calibration<N, Tables::Calibration>,
+    cogging: CoggingTable<N, Tables::Cogging>,
+    cogging_start: MultiTurnPosition,
+    update_frequency: i32,
     position_input: Input,
-    setpoint: i32,
+    last_input_position: i32,
+    encoder_position: MultiTurnPosition,
+    home_offset: i64,
+    homed: bool,
+    setpoint: MultiTurnPosition,
+    velocity: i32,
+    velocity_remainder: i32,
+    velocity_position: MultiTurnPosition,
+    measured_velocity: i32,
     speed: i32,
     detected_angle: i32,
     angle_setpoint: i32,
+    /// Both in fixed point, see `ANGLE_SCALE`.
+    detected_fine_angle: i32,
+    fine_angle_setpoint: i32,
+    pull_angle: i32,
+    /// In mA, see `set_drive_current` and `cogging_current`.
+    drive_current: i32,
+    cogging_current: i32,
     //interpolation_change: i32,
 }
-impl<Input> PositionControl<Input>
+impl<Input, const N: usize, Tables> PositionControl<Input, N, Tables>
 where
     Input: PositionInput,
+    Tables: TableStorage<N>,
 {
-    pub fn new(position_input: Input, _control_period: i32) -> Self {
+    /// `update_frequency` is the number of `update` calls per second.
+    pub fn new(
+        position_input: Input,
+        update_frequency: i32,
+        motor_config: MotorConfig,
+        encoder_config: EncoderConfig,
+        tables: Tables,
+    ) -> Self {
+        let last_input_position = position_input.get_position();
+        let (calibration, cogging) = tables.split();
         Self {
             mode: Mode::Normal,
-            calibration: Calibration::default(),
-            //control_period,
+            calibration: Calibration::new(motor_config, encoder_config, calibration),
+            cogging: CoggingTable::new(cogging),
+            cogging_start: MultiTurnPosition::ZERO,
+            update_frequency,
             position_input,
-            setpoint: 0,
+            last_input_position,
+            encoder_position: MultiTurnPosition::from(last_input_position),
+            home_offset: 0,
+            homed: false,
+            setpoint: MultiTurnPosition::ZERO,
+            velocity: 0,
+            velocity_remainder: 0,
+            velocity_position: MultiTurnPosition::from(last_input_position),
+            measured_velocity: 0,
             speed: 0,
             detected_angle: 0,
             angle_setpoint: 0,
+            detected_fine_angle: 0,
+            fine_angle_setpoint: 0,
+            pull_angle: 0,
+            drive_current: 0,
+            cogging_current: 0,
             //interpolation_change: 0,
         }
     }
@@ -51,11 +173,184 @@ where
     pub fn angle(&self) -> i32 {
         self.angle_setpoint
     }
-    pub fn set_position(&mut self, position: i32) {
+    /// `angle` with the resolution of the calibration table.
+    pub fn electrical_angle(&self) -> ElectricalAngle {
+        ElectricalAngle::from_fixed_point(self.fine_angle_setpoint, ANGLE_FRACTION_BITS)
+    }
+    /// Load in percent, from the angle the rotor is pulled with towards the
+    /// setpoint.
+    pub fn load(&self) -> i32 {
+        self.pull_angle * 100 / COIL_MAX_PULL_ANGLE
+    }
+    /// Absolute target, relative to the home position.
+    pub fn set_position(&mut self, position: MultiTurnPosition) {
+        self.cogging.cancel_measurement();
         self.setpoint = position;
+        if let Mode::Velocity | Mode::IndexHoming = self.mode {
+            self.mode = Mode::Normal;
+        }
+    }
+    pub fn get_position_setpoint(&self) -> MultiTurnPosition {
+        self.setpoint
+    }
+    /// Move the setpoint with `velocity` pulses per second, starting from
+    /// the current setpoint.
+    pub fn set_velocity(&mut self, velocity: i32) {
+        self.cogging.cancel_measurement();
+        self.velocity = velocity;
+        if let Mode::Normal = self.mode {
+            self.mode = Mode::Velocity;
+        }
+    }
+    /// Estimate the velocity from the position change since the last call,
+    /// `frequency` is the number of these calls per second.
+    pub fn update_velocity(&mut self, frequency: i32) {
+        let position = self.current_encoder_position();
+        let change = position.saturating_distance(self.velocity_position);
+        self.velocity_position = position;
+
+        let velocity = change.saturating_mul(frequency as i64);
+        let measured_velocity = self.measured_velocity as i64;
+        let measured_velocity =
+            measured_velocity + (velocity - measured_velocity) / VELOCITY_FILTER;
+        self.measured_velocity =
+            util::clamp(i32::MIN as i64, i32::MAX as i64, measured_velocity) as i32;
+    }
+    /// Measured velocity in pulses per second.
+    pub fn measured_velocity(&self) -> i32 {
+        self.measured_velocity
     }
-    pub fn get_current_position(&self) -> i32 {
-        self.position_input.get_position()
+    /// Pulses the position is behind the setpoint.
+    pub fn following_error(&self) -> i64 {
+        self.setpoint
+            .saturating_distance(self.get_current_position())
+    }
+    /// `following_error` in electrical degrees.
+    pub fn following_error_degrees(&self) -> i32 {
+        let cycles = self
+            .calibration
+            .motor_config()
+            .electrical_cycles_per_rotation() as i64;
+        let pulses_per_rotation = self.calibration.encoder_config().pulses_per_rotation() as i64;
+        let degrees =
+            self.following_error().saturating_mul(360 * cycles) / pulses_per_rotation.max(1);
+        util::clamp(i32::MIN as i64, i32::MAX as i64, degrees) as i32
+    }
+    /// Electrical cycles to encoder pulses, for the open loop speed.
+    pub fn cycles_to_pulses(&self, cycles: i32) -> i32 {
+        let (pulses_per_rotation, cycles_per_rotation) = self.rotation();
+        let pulses = cycles as i64 * pulses_per_rotation / cycles_per_rotation;
+        util::clamp(i32::MIN as i64, i32::MAX as i64, pulses) as i32
+    }
+    /// Encoder pulses to electrical cycles, rounded towards zero.
+    pub fn pulses_to_cycles(&self, pulses: i32) -> i32 {
+        let (pulses_per_rotation, cycles_per_rotation) = self.rotation();
+        (pulses as i64 * cycles_per_rotation / pulses_per_rotation) as i32
+    }
+    fn rotation(&self) -> (i64, i64) {
+        let pulses = self.calibration.encoder_config().pulses_per_rotation() as i64;
+        let cycles = self
+            .calibration
+            .motor_config()
+            .electrical_cycles_per_rotation() as i64;
+        (pulses.max(1), cycles.max(1))
+    }
+    /// Electrical angle of the rotor, from the calibration.
+    pub fn detected_angle(&self) -> i32 {
+        self.detected_angle
+    }
+    /// The setpoint moves with a velocity, instead of jumping to a target.
+    pub fn is_velocity_driven(&self) -> bool {
+        matches!(self.mode, Mode::Velocity | Mode::IndexHoming)
+    }
+    /// Continue from the current position, after the rotor lost the setpoint.
+    pub fn resync(&mut self) {
+        self.setpoint = self.get_current_position();
+        self.velocity_remainder = 0;
+    }
+    /// Make `home` (in the current coordinates) the zero position.
+    pub fn set_home(&mut self, home: MultiTurnPosition) {
+        self.home_offset = self.home_offset.saturating_add(home.pulses());
+        self.setpoint = self.setpoint.saturating_add(home.pulses().saturating_neg());
+        self.homed = true;
+    }
+    /// Position relative to the home position.
+    pub fn get_current_position(&self) -> MultiTurnPosition {
+        self.current_encoder_position()
+            .saturating_add(self.home_offset.saturating_neg())
+    }
+    pub fn position_input(&self) -> &Input {
+        &self.position_input
+    }
+    /// Includes the input change since the last `update_position`.
+    fn current_encoder_position(&self) -> MultiTurnPosition {
+        let change = self
+            .position_input
+            .get_position()
+            .wrapping_sub(self.last_input_position);
+        self.encoder_position.saturating_add(change as i64)
+    }
+    /// Restart the position tracking at the current input position.
+    fn sync_position(&mut self) {
+        self.last_input_position = self.position_input.get_position();
+        self.encoder_position = MultiTurnPosition::from(self.last_input_position);
+    }
+    pub fn is_homed(&self) -> bool {
+        self.homed
+    }
+    /// Move with `velocity` pulses per second until the index pulse is
+    /// found, which becomes the zero position. The input should not re-zero
+    /// on the index pulse itself.
+    pub fn start_index_homing(&mut self, velocity: i32) {
+        self.setpoint = self.get_current_position();
+        self.velocity = velocity;
+        self.velocity_remainder = 0;
+        self.homed = false;
+        self.position_input.take_index();
+        self.mode = Mode::IndexHoming;
+    }
+    /// Move one rotation with `velocity` pulses per second and measure the
+    /// detent torque, see `CoggingTable`.
+    pub fn start_cogging_measurement(&mut self, velocity: i32) {
+        self.setpoint = self.get_current_position();
+        self.cogging_start = self.setpoint;
+        self.velocity = velocity;
+        self.velocity_remainder = 0;
+        self.cogging.start_measurement();
+        self.mode = Mode::Velocity;
+    }
+    pub fn cogging(&mut self) -> &mut CoggingTable<N, Tables::Cogging> {
+        &mut self.cogging
+    }
+    /// Coil current the rotor is pulled with, the holding current of the
+    /// cogging measurement is its torque producing part.
+    pub fn set_drive_current(&mut self, current: i32) {
+        self.drive_current = current;
+    }
+    /// Current to add to the coils against the detent torque, at right
+    /// angles to the rotor.
+    pub fn cogging_current(&self) -> (ElectricalAngle, i32) {
+        let rotor =
+            ElectricalAngle::from_fixed_point(self.detected_fine_angle, ANGLE_FRACTION_BITS);
+        (
+            rotor.wrapping_add(ElectricalAngle::QUARTER),
+            self.cogging_current,
+        )
+    }
+    fn update_cogging_measurement(&mut self) {
+        let pulses_per_rotation = self.calibration.encoder_config().pulses_per_rotation() as i64;
+        let position = self.get_current_position();
+        if position.saturating_distance(self.cogging_start).abs() >= pulses_per_rotation {
+            self.cogging.finish_measurement();
+            self.setpoint = position;
+            self.mode = Mode::Normal;
+        }
+    }
+    fn advance_setpoint(&mut self) {
+        self.velocity_remainder += self.velocity;
+        let pulses = self.velocity_remainder / self.update_frequency.max(1);
+        self.velocity_remainder -= pulses * self.update_frequency.max(1);
+        self.setpoint = self.setpoint.saturating_add(pulses as i64);
     }
     pub fn set_speed(&mut self, speed: i32) {
         self.speed = speed;
@@ -65,12 +360,21 @@ where
             Mode::Normal => {
                 self.calculate_next_angle();
             }
+            Mode::Velocity | Mode::IndexHoming => {
+                self.advance_setpoint();
+                self.calculate_next_angle();
+                if self.cogging.is_measuring() {
+                    self.update_cogging_measurement();
+                }
+            }
             Mode::Calibration => {
                 self.calibration.update(&mut self.position_input);
+                self.sync_position();
                 if self.calibration.is_calibrated() {
                     self.mode = Mode::Normal;
                 } else {
                     self.angle_setpoint = self.calibration.requested_angle();
+                    self.fine_angle_setpoint = self.angle_setpoint * ANGLE_SCALE;
                 }
             }
         }
@@ -79,33 +383,49 @@ where
     pub fn update_position(&mut self) {
         self.position_input.update();
 
-        let position = self.position_input.get_position();
-        let position = if position > 0 {
-            position as usize % PULSES_PER_ROTATION
-        } else {
-            PULSES_PER_ROTATION - 1 - ((position % PULSES_PER_ROTATION as i32) * -1) as usize
-        };
+        let pulses_per_rotation = self.calibration.encoder_config().pulses_per_rotation();
+        self.encoder_position = self.current_encoder_position();
+        self.last_input_position = self.position_input.get_position();
+        let position = self.encoder_position.pulses_in_turn(pulses_per_rotation) as usize;
 
         match self.mode {
-            Mode::Normal => {
+            Mode::Normal | Mode::Velocity => {
+                self.detected_angle = self.calibration.angle_at_position(position);
+                self.detected_fine_angle = self.calibration.fine_angle_at_position(position);
+            }
+            Mode::IndexHoming => {
                 self.detected_angle = self.calibration.angle_at_position(position);
+                self.detected_fine_angle = self.calibration.fine_angle_at_position(position);
+                if let Some(index) = self.position_input.take_index() {
+                    let change = index.wrapping_sub(self.last_input_position);
+                    self.home_offset = self.encoder_position.saturating_add(change as i64).pulses();
+                    self.homed = true;
+                    self.setpoint = MultiTurnPosition::ZERO;
+                    self.mode = Mode::Normal;
+                }
             }
             Mode::Calibration => {
-                let position = self.position_input.get_position();
-                if position >= 0 && position < PULSES_PER_ROTATION as i32 {
+                if self.position_input.is_absolute() {
+                    // Any position within the rotation is valid.
                     self.calibration
-                        .update_position(position as usize, self.angle_setpoint);
+                        .update_position(position, self.angle_setpoint);
+                } else {
+                    let position = self.position_input.get_position();
+                    if position >= 0 && position < pulses_per_rotation as i32 {
+                        self.calibration
+                            .update_position(position as usize, self.angle_setpoint);
+                    }
                 }
             }
         }
     }
 
     fn calculate_next_angle(&mut self) {
-        const COIL_MAX_PULL_ANGLE: i32 = 90;
         const HALF_COIL_MAX_PULL_ANGLE: i32 = COIL_MAX_PULL_ANGLE / 2;
 
         let position = self.get_current_position();
-        let position_diff = position - self.setpoint;
+        let position_diff = position.saturating_distance(self.setpoint);
+        let position_diff = util::clamp(-(i32::MAX as i64), i32::MAX as i64, position_diff) as i32;
 
         // Prevent ossilations, reduce the pull as we are close.
         let diff = position_diff.abs();
@@ -114,6 +434,7 @@ where
             2..=HALF_COIL_MAX_PULL_ANGLE => diff * 2,
             _ => COIL_MAX_PULL_ANGLE,
         };
+        self.pull_angle = pull_angle;
 
         // Change new angle acording to position
         self.angle_setpoint = if position_diff.is_positive() {
@@ -121,6 +442,28 @@ where
         } else {
             self.detected_angle + pull_angle
         };
+        let fine_pull_angle = if position_diff.is_positive() {
+            -pull_angle * ANGLE_SCALE
+        } else {
+            pull_angle * ANGLE_SCALE
+        };
+
+        // Detent torque, measure or compensate.
+        let pulses_per_rotation = self.calibration.encoder_config().pulses_per_rotation();
+        let index = self.calibration.table_index(
+            self.current_encoder_position()
+                .pulses_in_turn(pulses_per_rotation) as usize,
+        );
+        self.cogging_current = if self.cogging.is_measuring() {
+            let pull = ElectricalAngle::from_fixed_point(fine_pull_angle, ANGLE_FRACTION_BITS);
+            self.cogging
+                .add_sample(index, lookup::sin(pull, self.drive_current));
+            0
+        } else {
+            self.cogging.compensation(index, self.measured_velocity)
+        };
+        self.fine_angle_setpoint =
+            (self.detected_fine_angle + fine_pull_angle).rem_euclid(360 * ANGLE_SCALE);
 
         if self.angle_setpoint.is_positive() {
             self.angle_setpoint %= 360;
@@ -134,7 +477,9 @@ where
     pub fn start_calibration(&mut self) {
         // Reset
         self.position_input.reset();
+        self.sync_position();
         self.calibration.reset();
+        self.cogging.invalidate();
         self.mode = Mode::Calibration;
     }
 
@@ -150,9 +495,13 @@ where
             self.mode = Mode::Calibration;
         }
     }
-    pub fn get_calibration_data(&self) -> &DebugCalibrationData {
+    pub fn get_calibration_data(&self) -> &DebugCalibrationData<N> {
         self.calibration.get_calibration_data()
     }
+    /// See `Calibration::harmonic_error`.
+    pub fn harmonic_error(&self) -> Option<(i32, i32)> {
+        self.calibration.harmonic_error()
+    }
     pub fn calibration_is_done(&self) -> bool {
         self.calibration.is_calibrated()
     }
@@ -165,6 +514,7 @@ mod tests {
     struct DummyInput {
         position: i32,
         direction: Direction,
+        index: Option<i32>,
     }
     impl PositionInput for DummyInput {
         fn update(&mut self) {}
@@ -177,39 +527,175 @@ mod tests {
         fn get_direction(&self) -> Direction {
             self.direction
         }
+        fn take_index(&mut self) -> Option<i32> {
+            self.index.take()
+        }
     }
 
-    #[test]
-    fn position_positive_diff() {
-        // Start at 0
+    fn position_control_at(
+        position: i32,
+    ) -> PositionControl<DummyInput, DEFAULT_TABLE_SIZE, Box<PositionTables>> {
         let input = DummyInput {
-            position: 0,
+            position,
             direction: Direction::Unknown(0),
+            index: None,
         };
-        let mut position_control = PositionControl::new(input, 10);
+        PositionControl::new(
+            input,
+            10,
+            MotorConfig::default(),
+            EncoderConfig::default(),
+            Box::new(PositionTables::new()),
+        )
+    }
+
+    #[test]
+    fn position_positive_diff() {
+        // Start at 0
+        let mut position_control = position_control_at(0);
 
         // Request a new position
-        position_control.set_position(500);
+        position_control.set_position(500.into());
         position_control.update();
 
         let next_angle = position_control.angle();
-        assert_eq!(0 + DEGREES_PER_ENCODER_PULSE, next_angle);
+        assert_eq!(COIL_MAX_PULL_ANGLE, next_angle);
+        assert_eq!(
+            ElectricalAngle::QUARTER,
+            position_control.electrical_angle()
+        );
     }
 
     #[test]
     fn position_negative_diff() {
         // Start at 0
-        let input = DummyInput {
-            position: 1000,
-            direction: Direction::Unknown(0),
-        };
-        let mut position_control = PositionControl::new(input, 10);
+        let mut position_control = position_control_at(1000);
 
         // Request a new position
-        position_control.set_position(500);
+        position_control.set_position(500.into());
         position_control.update();
 
         let next_angle = position_control.angle();
-        assert_eq!(360 - DEGREES_PER_ENCODER_PULSE, next_angle);
+        assert_eq!(360 - COIL_MAX_PULL_ANGLE, next_angle);
+        assert_eq!(next_angle, position_control.electrical_angle().degrees());
+    }
+
+    #[test]
+    fn cogging_measurement_sweep() {
+        let mut position_control = position_control_at(0);
+        position_control.start_cogging_measurement(100);
+        assert!(position_control.cogging().is_measuring());
+
+        for position in 1..=2400 {
+            assert!(position_control.is_velocity_driven());
+            position_control.position_input.position = position;
+            position_control.update_position();
+            position_control.update();
+        }
+        assert!(!position_control.is_velocity_driven());
+        assert!(!position_control.cogging().is_measuring());
+        assert!(position_control.cogging().is_valid());
+    }
+
+    #[test]
+    fn cogging_holding_current() {
+        let mut position_control = position_control_at(0);
+        position_control.set_drive_current(1000);
+        position_control.start_cogging_measurement(100);
+
+        // The rotor stays behind, pulled with 20 degrees.
+        position_control.update();
+        assert_eq!(20, position_control.angle());
+        let current = position_control.cogging().currents()[0] as i32;
+        assert!((current - 342).abs() <= 1, "{}", current);
+    }
+
+    #[test]
+    fn cogging_feed_forward() {
+        let mut position_control = position_control_at(0);
+        let cogging = position_control.cogging();
+        cogging.start_measurement();
+        cogging.add_sample(0, 200);
+        cogging.add_sample(1, -200);
+        cogging.finish_measurement();
+
+        // At right angles to the rotor, the setpoint angle is unchanged.
+        position_control.update();
+        let rotor = position_control.electrical_angle();
+        assert_eq!(
+            (rotor.wrapping_add(ElectricalAngle::QUARTER), 200),
+            position_control.cogging_current()
+        );
+        position_control.cogging().set_enabled(false);
+        position_control.update();
+        assert_eq!(rotor, position_control.electrical_angle());
+        assert_eq!(0, position_control.cogging_current().1);
+    }
+
+    #[test]
+    fn position_multi_turn() {
+        let mut position_control = position_control_at(i32::MAX - 10);
+
+        // The input wraps, the position keeps counting.
+        position_control.position_input.position = i32::MIN + 10;
+        position_control.update_position();
+        assert_eq!(
+            i32::MAX as i64 + 11,
+            position_control.get_current_position().pulses()
+        );
+
+        position_control.position_input.position = i32::MAX - 10;
+        position_control.update_position();
+        assert_eq!(
+            i32::MAX as i64 - 10,
+            position_control.get_current_position().pulses()
+        );
+    }
+
+    #[test]
+    fn velocity_and_home() {
+        let mut position_control = position_control_at(100);
+        position_control.set_position(100.into());
+        position_control.set_velocity(-30);
+        for _ in 0..10 {
+            position_control.update();
+        }
+        assert_eq!(70, position_control.get_position_setpoint().pulses());
+        assert_eq!(-30, position_control.following_error());
+
+        position_control.set_home(100.into());
+        assert_eq!(0, position_control.get_current_position().pulses());
+        assert_eq!(-30, position_control.get_position_setpoint().pulses());
+        assert!(position_control.is_homed());
+    }
+
+    #[test]
+    fn measure_velocity() {
+        let mut position_control = position_control_at(0);
+        for position in 1..=20 {
+            position_control.position_input.position = position * 5;
+            position_control.update_velocity(100);
+        }
+        assert!((position_control.measured_velocity() - 500).abs() < VELOCITY_FILTER as i32);
+    }
+
+    #[test]
+    fn index_homing() {
+        let mut position_control = position_control_at(100);
+        position_control.start_index_homing(20);
+        for _ in 0..10 {
+            position_control.update();
+        }
+        assert!(!position_control.is_homed());
+        assert_eq!(120, position_control.setpoint.pulses());
+
+        // Index seen at 110, the input moved on to 115.
+        position_control.position_input.index = Some(110);
+        position_control.position_input.position = 115;
+        position_control.update_position();
+
+        assert!(position_control.is_homed());
+        assert_eq!(5, position_control.get_current_position().pulses());
+        assert_eq!(MultiTurnPosition::ZERO, position_control.setpoint);
     }
 }
@@ -0,0 +1,275 @@
+use crate::position_control::{Direction, PositionInput};
+
+/// Marks a transition where both A and B changed, the direction is lost.
+const ILLEGAL: i8 = 2;
+
+/// Count change, indexed by `previous state << 2 | new state` where a
+/// state is `A << 1 | B`. Forward is A leading B: 00 -> 10 -> 11 -> 01.
+#[rustfmt::skip]
+const TRANSITIONS: [i8; 16] = [
+    // new:   00       01       10       11
+    /* 00 */  0,       -1,      1,       ILLEGAL,
+    /* 01 */  1,       0,       ILLEGAL, -1,
+    /* 10 */  -1,      ILLEGAL, 0,       1,
+    /* 11 */  ILLEGAL, 1,       -1,      0,
+];
+
+/// What to do with the Z (index) pulse.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexMode {
+    Ignore,
+    /// Zero the count on the first index pulse only.
+    ZeroOnce,
+    /// Zero the count on every index pulse.
+    ZeroAlways,
+}
+
+/// 4x quadrature decoder working on the raw pin states, `sample` is meant to
+/// be called from the pin change or timer ISR. The count is latched by
+/// `PositionInput::update`.
+pub struct QuadratureDecoder {
+    state: u8,
+    pending_state: u8,
+    pending_samples: u8,
+    filter_samples: u8,
+    count: i32,
+    index_mode: IndexMode,
+    index_level: bool,
+    index_count: Option<i32>,
+    index_event: Option<i32>,
+    illegal_transitions: u32,
+    glitches: u32,
+    position: i32,
+    direction: Direction,
+}
+
+impl QuadratureDecoder {
+    /// `a` and `b` are the pin states at startup.
+    pub fn new(a: bool, b: bool, index_mode: IndexMode) -> Self {
+        let state = Self::to_state(a, b);
+        Self {
+            state,
+            pending_state: state,
+            pending_samples: 0,
+            filter_samples: 0,
+            count: 0,
+            index_mode,
+            index_level: false,
+            index_count: None,
+            index_event: None,
+            illegal_transitions: 0,
+            glitches: 0,
+            position: 0,
+            direction: Direction::Unknown(0),
+        }
+    }
+
+    /// A new pin state is only accepted after it was sampled `samples` times
+    /// in a row, shorter pulses are counted as glitch.
+    pub fn with_glitch_filter(mut self, samples: u8) -> Self {
+        self.filter_samples = samples;
+        self
+    }
+
+    fn to_state(a: bool, b: bool) -> u8 {
+        (a as u8) << 1 | b as u8
+    }
+
+    /// Decode the A/B pin states.
+    pub fn sample(&mut self, a: bool, b: bool) {
+        let state = Self::to_state(a, b);
+
+        if self.filter_samples > 1 {
+            if state == self.state {
+                if self.pending_state != self.state {
+                    self.glitches = self.glitches.wrapping_add(1);
+                    self.pending_state = self.state;
+                }
+                return;
+            }
+            if state != self.pending_state {
+                self.pending_state = state;
+                self.pending_samples = 0;
+            }
+            self.pending_samples = self.pending_samples.saturating_add(1);
+            if self.pending_samples < self.filter_samples {
+                return;
+            }
+        }
+
+        match TRANSITIONS[((self.state << 2) | state) as usize] {
+            ILLEGAL => self.illegal_transitions = self.illegal_transitions.wrapping_add(1),
+            change => self.count = self.count.wrapping_add(change as i32),
+        }
+        self.state = state;
+        self.pending_state = state;
+    }
+
+    /// Decode the A/B pin states plus the Z (index) pin.
+    pub fn sample_with_index(&mut self, a: bool, b: bool, z: bool) {
+        self.sample(a, b);
+
+        let rising_edge = z && !self.index_level;
+        self.index_level = z;
+        if rising_edge {
+            let zero = match self.index_mode {
+                IndexMode::Ignore => false,
+                IndexMode::ZeroOnce => self.index_count.is_none(),
+                IndexMode::ZeroAlways => true,
+            };
+            self.index_count = Some(self.count);
+            if zero {
+                self.count = 0;
+            }
+            self.index_event = Some(self.count);
+        }
+    }
+
+    /// Count at the last index pulse, before any re-zero.
+    pub fn index_count(&self) -> Option<i32> {
+        self.index_count
+    }
+    pub fn illegal_transitions(&self) -> u32 {
+        self.illegal_transitions
+    }
+    pub fn glitches(&self) -> u32 {
+        self.glitches
+    }
+}
+
+impl PositionInput for QuadratureDecoder {
+    fn update(&mut self) {
+        let change = self.count.wrapping_sub(self.position);
+        self.position = self.count;
+        self.direction = match change {
+            0 => Direction::Unknown(0),
+            c if c > 0 => Direction::Increased(c),
+            c => Direction::Decreased(-c),
+        };
+    }
+    fn reset(&mut self) {
+        self.count = 0;
+        self.position = 0;
+        self.direction = Direction::Unknown(0);
+    }
+    fn get_position(&self) -> i32 {
+        self.position
+    }
+    fn get_direction(&self) -> Direction {
+        self.direction
+    }
+    fn take_index(&mut self) -> Option<i32> {
+        self.index_event.take()
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORWARD: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+
+    fn play(decoder: &mut QuadratureDecoder, sequence: &[(bool, bool)]) {
+        for (a, b) in sequence {
+            decoder.sample(*a, *b);
+        }
+    }
+
+    #[test]
+    fn count_forward() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::Ignore);
+        play(&mut decoder, &FORWARD);
+        play(&mut decoder, &FORWARD);
+        decoder.update();
+
+        assert_eq!(8, decoder.get_position());
+        assert!(matches!(decoder.get_direction(), Direction::Increased(8)));
+    }
+
+    #[test]
+    fn count_backward() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::Ignore);
+        let mut backward = FORWARD;
+        backward.reverse();
+        play(&mut decoder, &backward[1..]);
+        play(&mut decoder, &[(false, false)]);
+        decoder.update();
+
+        assert_eq!(-4, decoder.get_position());
+        assert!(matches!(decoder.get_direction(), Direction::Decreased(4)));
+    }
+
+    #[test]
+    fn repeated_state_is_no_change() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::Ignore);
+        play(&mut decoder, &[(true, false), (true, false), (true, false)]);
+        decoder.update();
+
+        assert_eq!(1, decoder.get_position());
+        decoder.update();
+        assert!(matches!(decoder.get_direction(), Direction::Unknown(0)));
+    }
+
+    #[test]
+    fn illegal_transition() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::Ignore);
+        play(&mut decoder, &[(true, true), (false, false)]);
+        decoder.update();
+
+        assert_eq!(0, decoder.get_position());
+        assert_eq!(2, decoder.illegal_transitions());
+    }
+
+    #[test]
+    fn glitch_filter() {
+        let mut decoder =
+            QuadratureDecoder::new(false, false, IndexMode::Ignore).with_glitch_filter(2);
+
+        // Single sample spike is rejected.
+        play(&mut decoder, &[(true, false), (false, false)]);
+        decoder.update();
+        assert_eq!(0, decoder.get_position());
+        assert_eq!(1, decoder.glitches());
+
+        // Stable state is accepted.
+        play(&mut decoder, &[(true, false), (true, false)]);
+        decoder.update();
+        assert_eq!(1, decoder.get_position());
+    }
+
+    #[test]
+    fn index_zero_once() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::ZeroOnce);
+        play(&mut decoder, &FORWARD);
+        decoder.sample_with_index(true, false, true);
+        decoder.sample_with_index(true, true, false);
+        decoder.update();
+
+        assert_eq!(Some(5), decoder.index_count());
+        assert_eq!(Some(0), decoder.take_index());
+        assert_eq!(None, decoder.take_index());
+        assert_eq!(1, decoder.get_position());
+
+        play(&mut decoder, &FORWARD[2..]);
+        decoder.sample_with_index(true, false, true);
+        decoder.update();
+        assert_eq!(4, decoder.get_position());
+    }
+
+    #[test]
+    fn index_zero_always() {
+        let mut decoder = QuadratureDecoder::new(false, false, IndexMode::ZeroAlways);
+        play(&mut decoder, &FORWARD);
+        decoder.sample_with_index(true, false, true);
+        decoder.sample_with_index(true, true, false);
+        play(&mut decoder, &FORWARD[2..]);
+        decoder.sample_with_index(true, false, true);
+        decoder.update();
+
+        assert_eq!(0, decoder.get_position());
+    }
+}
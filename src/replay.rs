@@ -0,0 +1,395 @@
+//! Recording of a running drive, to replay it on the host. A recording is a
+//! stream of entries, all values little endian:
+//!
+//! - header, `H`: the `CurrentControl` and motor setup with the ADC offsets
+//!   measured so far, first in the stream;
+//! - sample, `S`: one current loop period, ADC values and their phase in,
+//!   outputs out;
+//! - command, `C`: length and text of a serial command, applied before the
+//!   next sample.
+
+use crate::config::{EncoderConfig, MotorConfig};
+use crate::current_control::{
+    CurrentFilter, CurrentSense, DecayConfig, PulseConfig, SamplePhase, SamplingConfig, SenseMode,
+    ZeroConfig,
+};
+
+const HEADER_TAG: u8 = b'H';
+const SAMPLE_TAG: u8 = b'S';
+const COMMAND_TAG: u8 = b'C';
+
+pub const HEADER_SIZE: usize = 1 + 27 * 4;
+pub const SAMPLE_SIZE: usize = 1 + 2 + 2 + 4 + 2 + 2 + 1 + 1 + 2;
+pub const MAX_COMMAND_LENGTH: usize = u8::MAX as usize;
+
+/// Setup of the drive, to rebuild it for the replay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    /// As configured, see `offsets` for the measured ADC offsets.
+    pub sense: CurrentSense,
+    pub zero: ZeroConfig,
+    /// Of coil A and B, see `CurrentControl::set_measured_offset`.
+    pub offsets: [u32; 2],
+    pub filter: CurrentFilter,
+    pub sampling: SamplingConfig,
+    pub decay: DecayConfig,
+    pub pulse: PulseConfig,
+    pub max_output_value: i32,
+    pub p: i32,
+    pub i: i32,
+    pub d: i32,
+    pub motor: MotorConfig,
+    pub encoder: EncoderConfig,
+}
+
+/// One current loop period: the ADC values given to `add_sample_at`, the
+/// position input after its update, and the resulting output values.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    pub adc_a: u16,
+    pub adc_b: u16,
+    pub position: i32,
+    pub output_a: i16,
+    pub output_b: i16,
+    /// Times `MotorControl::update` ran after the current loop.
+    pub updates: u8,
+    /// Of both ADC values.
+    pub phase: SamplePhase,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Entry<'a> {
+    Header(Header),
+    Sample(Sample),
+    Command(&'a str),
+}
+
+/// Unknown or truncated entry at `offset` bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FormatError {
+    pub offset: usize,
+}
+
+/// Writes the entries into a fixed buffer, in RAM on the drive.
+pub struct Recorder<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, length: 0 }
+    }
+    /// The recording so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+    pub fn is_full(&self) -> bool {
+        self.length + SAMPLE_SIZE > self.buffer.len()
+    }
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    /// False when there is no room left, the entry is not written.
+    pub fn header(&mut self, header: &Header) -> bool {
+        let mut entry = [0; HEADER_SIZE];
+        entry[0] = HEADER_TAG;
+        let values = [
+            header.sense.shunt_resistance,
+            header.sense.gain,
+            header.sense.reference_voltage,
+            header.sense.adc_max_value,
+            header.sense.offset,
+            match header.sense.mode {
+                SenseMode::Unipolar => 0,
+                SenseMode::Bipolar => 1,
+            },
+            header.zero.samples,
+            header.zero.max_deviation,
+            header.zero.on_enable as u32,
+            header.zero.idle_interval,
+            header.offsets[0],
+            header.offsets[1],
+            match header.filter {
+                CurrentFilter::None => 0,
+                CurrentFilter::Boxcar { .. } => 1,
+                CurrentFilter::Iir { .. } => 2,
+                CurrentFilter::Median { .. } => 3,
+            },
+            match header.filter {
+                CurrentFilter::None => 0,
+                CurrentFilter::Boxcar { length } | CurrentFilter::Median { length } => {
+                    length as u32
+                }
+                CurrentFilter::Iir { weight } => weight,
+            },
+            header.sampling.settle_time as u32,
+            header.sampling.acquisition_time as u32,
+            header.decay.mixed_threshold as u32,
+            header.decay.fast_threshold as u32,
+            header.pulse.dead_time as u32,
+            header.pulse.dead_time_band as u32,
+            header.pulse.min_pulse as u32,
+            header.max_output_value as u32,
+            header.p as u32,
+            header.i as u32,
+            header.d as u32,
+            header.motor.rotor_teeth,
+            header.encoder.lines,
+        ];
+        for (bytes, value) in entry[1..].chunks_exact_mut(4).zip(values.iter()) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        self.write(&entry)
+    }
+
+    pub fn sample(&mut self, sample: &Sample) -> bool {
+        let mut entry = [0; SAMPLE_SIZE];
+        entry[0] = SAMPLE_TAG;
+        entry[1..3].copy_from_slice(&sample.adc_a.to_le_bytes());
+        entry[3..5].copy_from_slice(&sample.adc_b.to_le_bytes());
+        entry[5..9].copy_from_slice(&sample.position.to_le_bytes());
+        entry[9..11].copy_from_slice(&sample.output_a.to_le_bytes());
+        entry[11..13].copy_from_slice(&sample.output_b.to_le_bytes());
+        entry[13] = sample.updates;
+        let (phase, after_edge) = match sample.phase {
+            SamplePhase::Center => (0, 0),
+            SamplePhase::OnTime { after_edge } => (1, after_edge as i16),
+        };
+        entry[14] = phase;
+        entry[15..17].copy_from_slice(&after_edge.to_le_bytes());
+        self.write(&entry)
+    }
+
+    /// Longer commands than `MAX_COMMAND_LENGTH` are not written.
+    pub fn command(&mut self, command: &str) -> bool {
+        let length = command.len();
+        if length > MAX_COMMAND_LENGTH || self.length + 2 + length > self.buffer.len() {
+            return false;
+        }
+        self.write(&[COMMAND_TAG, length as u8]) && self.write(command.as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> bool {
+        let end = self.length + bytes.len();
+        match self.buffer.get_mut(self.length..end) {
+            Some(buffer) => {
+                buffer.copy_from_slice(bytes);
+                self.length = end;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The entries of a recording, stops after the first error.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn next_entry(&self) -> Option<(Entry<'a>, usize)> {
+        let data = &self.data[self.offset..];
+        match *data.first()? {
+            HEADER_TAG => {
+                let entry = data.get(1..HEADER_SIZE)?;
+                let mut values = entry
+                    .chunks_exact(4)
+                    .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+                let mut value = || values.next().unwrap_or(0);
+                let header = Header {
+                    sense: CurrentSense {
+                        shunt_resistance: value(),
+                        gain: value(),
+                        reference_voltage: value(),
+                        adc_max_value: value(),
+                        offset: value(),
+                        mode: match value() {
+                            0 => SenseMode::Unipolar,
+                            _ => SenseMode::Bipolar,
+                        },
+                    },
+                    zero: ZeroConfig {
+                        samples: value(),
+                        max_deviation: value(),
+                        on_enable: value() != 0,
+                        idle_interval: value(),
+                    },
+                    offsets: [value(), value()],
+                    filter: match (value(), value()) {
+                        (1, length) => CurrentFilter::Boxcar {
+                            length: length as usize,
+                        },
+                        (2, weight) => CurrentFilter::Iir { weight },
+                        (3, length) => CurrentFilter::Median {
+                            length: length as usize,
+                        },
+                        _ => CurrentFilter::None,
+                    },
+                    sampling: SamplingConfig {
+                        settle_time: value() as i32,
+                        acquisition_time: value() as i32,
+                    },
+                    decay: DecayConfig {
+                        mixed_threshold: value() as i32,
+                        fast_threshold: value() as i32,
+                    },
+                    pulse: PulseConfig {
+                        dead_time: value() as i32,
+                        dead_time_band: value() as i32,
+                        min_pulse: value() as i32,
+                    },
+                    max_output_value: value() as i32,
+                    p: value() as i32,
+                    i: value() as i32,
+                    d: value() as i32,
+                    motor: MotorConfig {
+                        rotor_teeth: value(),
+                    },
+                    encoder: EncoderConfig { lines: value() },
+                };
+                Some((Entry::Header(header), HEADER_SIZE))
+            }
+            SAMPLE_TAG => {
+                let entry = data.get(..SAMPLE_SIZE)?;
+                let u16_at = |at: usize| u16::from_le_bytes([entry[at], entry[at + 1]]);
+                let sample = Sample {
+                    adc_a: u16_at(1),
+                    adc_b: u16_at(3),
+                    position: i32::from_le_bytes([entry[5], entry[6], entry[7], entry[8]]),
+                    output_a: u16_at(9) as i16,
+                    output_b: u16_at(11) as i16,
+                    updates: entry[13],
+                    phase: match entry[14] {
+                        0 => SamplePhase::Center,
+                        _ => SamplePhase::OnTime {
+                            after_edge: u16_at(15) as i16 as i32,
+                        },
+                    },
+                };
+                Some((Entry::Sample(sample), SAMPLE_SIZE))
+            }
+            COMMAND_TAG => {
+                let length = *data.get(1)? as usize;
+                let text = data.get(2..2 + length)?;
+                let command = core::str::from_utf8(text).ok()?;
+                Some((Entry::Command(command), 2 + length))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, FormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        match self.next_entry() {
+            Some((entry, size)) => {
+                self.offset += size;
+                Some(Ok(entry))
+            }
+            None => {
+                let offset = self.offset;
+                self.offset = self.data.len();
+                Some(Err(FormatError { offset }))
+            }
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_read() {
+        let header = Header {
+            sense: CurrentSense::bipolar(250, 5000, 4096),
+            zero: ZeroConfig {
+                on_enable: true,
+                idle_interval: 500_000,
+                ..ZeroConfig::default()
+            },
+            offsets: [2040, 2061],
+            filter: CurrentFilter::Median { length: 5 },
+            sampling: SamplingConfig {
+                settle_time: 20,
+                acquisition_time: 8,
+            },
+            decay: DecayConfig {
+                mixed_threshold: 30,
+                fast_threshold: 120,
+            },
+            pulse: PulseConfig {
+                dead_time: 12,
+                dead_time_band: 40,
+                min_pulse: 15,
+            },
+            max_output_value: 1000,
+            p: 2,
+            i: 2,
+            d: 0,
+            motor: MotorConfig::STEP_1_8_DEGREE,
+            encoder: EncoderConfig::default(),
+        };
+        let sample = Sample {
+            adc_a: 2110,
+            adc_b: 7,
+            position: -1200,
+            output_a: -1000,
+            output_b: 12,
+            updates: 1,
+            phase: SamplePhase::OnTime { after_edge: 30 },
+        };
+
+        let mut buffer = [0; HEADER_SIZE + SAMPLE_SIZE + 8];
+        let mut recorder = Recorder::new(&mut buffer);
+        assert!(recorder.header(&header));
+        assert!(recorder.command("p 1200"));
+        assert!(recorder.sample(&sample));
+        assert!(recorder.is_full());
+        assert!(!recorder.sample(&sample));
+
+        let entries: Vec<_> = Entries::new(recorder.data()).collect();
+        assert_eq!(
+            vec![
+                Ok(Entry::Header(header)),
+                Ok(Entry::Command("p 1200")),
+                Ok(Entry::Sample(sample)),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn corrupt_recording() {
+        let mut buffer = [0; 64];
+        let mut recorder = Recorder::new(&mut buffer);
+        recorder.command("e");
+        recorder.sample(&Sample::default());
+        let length = recorder.data().len();
+
+        let mut entries = Entries::new(&buffer[..length - 1]);
+        assert_eq!(Some(Ok(Entry::Command("e"))), entries.next());
+        assert_eq!(Some(Err(FormatError { offset: 3 })), entries.next());
+        assert_eq!(None, entries.next());
+
+        let mut entries = Entries::new(b"X");
+        assert_eq!(Some(Err(FormatError { offset: 0 })), entries.next());
+    }
+}
@@ -1,3 +1,7 @@
+use crate::motor_control::Status;
+use crate::soft_limits::LimitViolation;
+use crate::switches::Side;
+use core::fmt;
 use core::str;
 use core::str::FromStr;
 
@@ -58,17 +62,64 @@ impl Buffer {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Enable,
+    /// Controlled disable, see `StopConfig`.
     Disable,
-    Rotate { speed: i32 },
+    /// Disable without decelerating.
+    DisableNow,
+    /// Decelerate to standstill.
+    QuickStop,
+    Rotate {
+        speed: i32,
+    },
     Hold,
-    Cur { current: i32 },
-    Position { position: i32 },
-    Speed { speed: i32 },
-    PositionAndSpeed { position: i32, speed: i32 },
+    Cur {
+        current: i32,
+    },
+    Position {
+        position: i32,
+    },
+    Speed {
+        speed: i32,
+    },
+    PositionAndSpeed {
+        position: i32,
+        speed: i32,
+    },
+    PositionTurns {
+        turns: i32,
+        pulses: i32,
+    },
+    HomeIndex {
+        speed: i32,
+    },
+    Home {
+        direction: i32,
+        speed: i32,
+        offset: i32,
+    },
+    SoftLimits {
+        min: i32,
+        max: i32,
+    },
+    ResetEstop,
+    /// Clear a stall or sense fault.
+    ResetFault,
+    Status,
     P(i32),
     I(i32),
     D(i32),
     Calibrate,
+    MeasureCogging {
+        velocity: i32,
+    },
+    /// 0 for a sine, 1 for the harmonic corrected waveform.
+    SelectWaveform {
+        waveform: i32,
+    },
+    WaveformPoint {
+        index: i32,
+        value: i32,
+    },
     ShowCalData,
     ForceDuty(i32),
 }
@@ -81,6 +132,8 @@ impl Command {
         match command.next() {
             Some("e") => Some(Command::Enable),
             Some("d") => Some(Command::Disable),
+            Some("dn") => Some(Command::DisableNow),
+            Some("qs") => Some(Command::QuickStop),
             Some("r") => Some(Command::Rotate {
                 speed: Command::with_value(&mut command)?,
             }),
@@ -98,10 +151,39 @@ impl Command {
                 position: Command::with_value(&mut command)?,
                 speed: Command::with_value(&mut command)?,
             }),
+            Some("pt") => Some(Command::PositionTurns {
+                turns: Command::with_value(&mut command)?,
+                pulses: Command::with_value(&mut command)?,
+            }),
+            Some("hi") => Some(Command::HomeIndex {
+                speed: Command::with_value(&mut command)?,
+            }),
+            Some("home") => Some(Command::Home {
+                direction: Command::with_value(&mut command)?,
+                speed: Command::with_value(&mut command)?,
+                offset: Command::with_value(&mut command)?,
+            }),
+            Some("lim") => Some(Command::SoftLimits {
+                min: Command::with_value(&mut command)?,
+                max: Command::with_value(&mut command)?,
+            }),
+            Some("reset") => Some(Command::ResetEstop),
+            Some("rf") => Some(Command::ResetFault),
+            Some("status") => Some(Command::Status),
             Some("mp") => Some(Command::P(Command::with_value(&mut command)?)),
             Some("mi") => Some(Command::I(Command::with_value(&mut command)?)),
             Some("md") => Some(Command::D(Command::with_value(&mut command)?)),
             Some("cal") => Some(Command::Calibrate),
+            Some("cog") => Some(Command::MeasureCogging {
+                velocity: Command::with_value(&mut command)?,
+            }),
+            Some("wfs") => Some(Command::SelectWaveform {
+                waveform: Command::with_value(&mut command)?,
+            }),
+            Some("wf") => Some(Command::WaveformPoint {
+                index: Command::with_value(&mut command)?,
+                value: Command::with_value(&mut command)?,
+            }),
             Some("cal_data") => Some(Command::ShowCalData),
             Some("duty") => Some(Command::ForceDuty(Command::with_value(&mut command)?)),
             _ => None,
@@ -116,18 +198,87 @@ impl Command {
     }
 }
 
-pub struct SerialCommands {
-    buffer: Buffer,
+/// A command with arguments the drive can not carry out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandError {
+    /// The pulses of `pt` are not within one turn.
+    PulsesOutOfRange(i32),
+    /// The E-stop input is still active, the stop stays latched.
+    EstopActive,
+    /// The harmonic waveform needs a calibration.
+    NotCalibrated,
 }
 
-impl Default for SerialCommands {
-    fn default() -> Self {
-        Self {
-            buffer: Buffer::default(),
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::PulsesOutOfRange(pulses) => write!(f, "pulses out of range: {}", pulses),
+            CommandError::EstopActive => write!(f, "estop active"),
+            CommandError::NotCalibrated => write!(f, "not calibrated"),
+        }
+    }
+}
+
+/// Answers to the commands, written back over the serial line.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Response {
+    Ok,
+    LimitViolation(LimitViolation),
+    Status(Status),
+    Error(CommandError),
+}
+
+impl From<LimitViolation> for Response {
+    fn from(violation: LimitViolation) -> Self {
+        Response::LimitViolation(violation)
+    }
+}
+
+impl From<CommandError> for Response {
+    fn from(error: CommandError) -> Self {
+        Response::Error(error)
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Ok => write!(f, "ok"),
+            Response::Status(status) => write!(
+                f,
+                "enabled {} estop {} stalls {} fault {} sense {} pos {} err {}",
+                status.enabled as u8,
+                status.estop_latched as u8,
+                status.stall_count,
+                status.stall_fault as u8,
+                status.sense_fault as u8,
+                status.position.pulses(),
+                status.following_error
+            ),
+            Response::LimitViolation(violation) => write!(
+                f,
+                "limit {} {}: {}",
+                match violation.side {
+                    Side::Negative => "min",
+                    Side::Positive => "max",
+                },
+                if violation.clamped {
+                    "clamped"
+                } else {
+                    "rejected"
+                },
+                violation.target.pulses()
+            ),
+            Response::Error(error) => write!(f, "error {}", error),
         }
     }
 }
 
+#[derive(Default)]
+pub struct SerialCommands {
+    buffer: Buffer,
+}
+
 const ASCII_CR: u8 = b'\r';
 impl SerialCommands {
     pub fn add_character(&mut self, data: u8) {
@@ -194,13 +345,71 @@ mod tests {
 
     #[test]
     fn command_parsing() {
-        let data = "cur 100".split_whitespace();
+        let data = "c 100".split_whitespace();
         let command = Command::parse_from(data);
         assert_eq!(Some(Command::Cur { current: 100 }), command);
 
-        let data = "cur -5".split_whitespace();
+        let data = "c -5".split_whitespace();
         let command = Command::parse_from(data);
         assert_eq!(Some(Command::Cur { current: -5 }), command);
+
+        let data = "pt -3 100".split_whitespace();
+        let command = Command::parse_from(data);
+        assert_eq!(
+            Some(Command::PositionTurns {
+                turns: -3,
+                pulses: 100
+            }),
+            command
+        );
+    }
+
+    #[test]
+    fn parse_home_command() {
+        let data = "home -1 2400 100".split_whitespace();
+        let command = Command::parse_from(data);
+        assert_eq!(
+            Some(Command::Home {
+                direction: -1,
+                speed: 2400,
+                offset: 100
+            }),
+            command
+        );
+        assert_eq!(None, Command::parse_from("home -1 2400".split_whitespace()));
+    }
+
+    #[test]
+    fn limit_violation_response() {
+        let response = Response::from(LimitViolation {
+            side: Side::Positive,
+            target: 5000.into(),
+            clamped: false,
+        });
+        assert_eq!("limit max rejected: 5000", format!("{}", response));
+        assert_eq!("ok", format!("{}", Response::Ok));
+        assert_eq!(
+            "error pulses out of range: -100",
+            format!("{}", Response::from(CommandError::PulsesOutOfRange(-100)))
+        );
+        assert_eq!(
+            "error estop active",
+            format!("{}", Response::from(CommandError::EstopActive))
+        );
+
+        let response = Response::Status(Status {
+            enabled: true,
+            estop_latched: false,
+            stall_count: 2,
+            stall_fault: false,
+            sense_fault: true,
+            position: (-1200).into(),
+            following_error: 5,
+        });
+        assert_eq!(
+            "enabled 1 estop 0 stalls 2 fault 0 sense 1 pos -1200 err 5",
+            format!("{}", response)
+        );
     }
 
     #[test]
@@ -208,7 +417,7 @@ mod tests {
         // Register for the expected command
         let mut serial_commands = SerialCommands::default();
 
-        for data in b"disable\r" {
+        for data in b"d\r" {
             serial_commands.add_character(*data);
         }
 
@@ -219,7 +428,7 @@ mod tests {
     #[test]
     fn parse_command_leading_chars() {
         let mut serial_commands = SerialCommands::default();
-        for data in b"le _ 1 disable\r" {
+        for data in b"le _ 1 d\r" {
             serial_commands.add_character(*data);
         }
 
@@ -232,7 +441,7 @@ mod tests {
         // Register for the expected command
         let mut serial_commands = SerialCommands::default();
 
-        for data in b"cur 100\r" {
+        for data in b"c 100\r" {
             serial_commands.add_character(*data);
         }
 
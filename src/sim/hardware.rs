@@ -0,0 +1,146 @@
+use super::plant::{Phase, Plant};
+use crate::current_control::{CurrentOutput, CurrentSense, SenseMode};
+use crate::position_control::{Direction, PositionInput};
+use core::cell::RefCell;
+use core::f64::consts::PI;
+use std::rc::Rc;
+
+/// ADC value for `current` through the shunt of the `sense` chain. A
+/// unipolar chain has a low side shunt, it sees the current in the direction
+/// the bridge drives with `duty`. A current against the drive direction is
+/// below the ADC range.
+pub fn adc_value(sense: &CurrentSense, current: f64, duty: f64) -> u32 {
+    let current = match sense.mode {
+        SenseMode::Bipolar => current,
+        SenseMode::Unipolar if duty >= 0.0 => current.max(0.0),
+        SenseMode::Unipolar => (-current).max(0.0),
+    };
+    let voltage = current * sense.shunt_resistance as f64 * sense.gain as f64 / 1000.0;
+    let value =
+        voltage / sense.reference_voltage as f64 * sense.adc_max_value as f64 + sense.offset as f64;
+    (value.max(0.0) as u32).min(sense.adc_max_value - 1)
+}
+
+/// PWM bridge of one phase.
+pub struct SimOutput {
+    plant: Rc<RefCell<Plant>>,
+    phase: Phase,
+    max_value: i32,
+}
+
+impl SimOutput {
+    pub fn new(plant: Rc<RefCell<Plant>>, phase: Phase, max_value: i32) -> Self {
+        Self {
+            plant,
+            phase,
+            max_value,
+        }
+    }
+}
+
+impl CurrentOutput for SimOutput {
+    fn set_output_value(&mut self, value: i32) {
+        let duty = value as f64 / self.max_value as f64;
+        self.plant.borrow_mut().set_duty(self.phase, duty);
+    }
+    fn enable(&mut self, enable: bool) {
+        self.plant.borrow_mut().set_enabled(self.phase, enable);
+    }
+    fn get_max_output_value(&mut self) -> i32 {
+        self.max_value
+    }
+}
+
+/// Incremental encoder on the rotor shaft.
+pub struct SimEncoder {
+    plant: Rc<RefCell<Plant>>,
+    pulses_per_rotation: u32,
+    zero: i64,
+    position: i32,
+    direction: Direction,
+}
+
+impl SimEncoder {
+    pub fn new(plant: Rc<RefCell<Plant>>, pulses_per_rotation: u32) -> Self {
+        let mut encoder = Self {
+            plant,
+            pulses_per_rotation,
+            zero: 0,
+            position: 0,
+            direction: Direction::Unknown(0),
+        };
+        encoder.reset();
+        encoder
+    }
+
+    fn count(&self) -> i64 {
+        let rotations = self.plant.borrow().angle() / (2.0 * PI);
+        (rotations * self.pulses_per_rotation as f64).floor() as i64
+    }
+}
+
+impl PositionInput for SimEncoder {
+    fn update(&mut self) {
+        let position = self.count().wrapping_sub(self.zero) as i32;
+        let change = position.wrapping_sub(self.position);
+        self.position = position;
+        self.direction = match change {
+            0 => Direction::Unknown(0),
+            c if c > 0 => Direction::Increased(c),
+            c => Direction::Decreased(-c),
+        };
+    }
+    fn reset(&mut self) {
+        self.zero = self.count();
+        self.position = 0;
+        self.direction = Direction::Unknown(0);
+    }
+    fn get_position(&self) -> i32 {
+        self.position
+    }
+    fn get_direction(&self) -> Direction {
+        self.direction
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::plant::StepperParameters;
+
+    #[test]
+    fn encoder_counts_rotor() {
+        let plant = Rc::new(RefCell::new(Plant::new(StepperParameters::default())));
+        let mut encoder = SimEncoder::new(plant.clone(), 2400);
+
+        plant.borrow_mut().set_angle(-PI / 2.0);
+        encoder.update();
+        assert_eq!(-600, encoder.get_position());
+        assert!(matches!(encoder.get_direction(), Direction::Decreased(600)));
+
+        encoder.reset();
+        plant.borrow_mut().set_angle(0.0);
+        encoder.update();
+        assert_eq!(600, encoder.get_position());
+    }
+
+    #[test]
+    fn adc_value_of_current() {
+        let sense = CurrentSense::unipolar(250, 0, 4096);
+        // 1 A: 250 mV * 6.8 = 1700 mV.
+        assert_eq!(2110, adc_value(&sense, 1.0, 0.5));
+        assert_eq!(2110, adc_value(&sense, -1.0, -0.5));
+        assert_eq!(0, adc_value(&sense, 1.0, -0.5));
+        assert_eq!(4095, adc_value(&sense, 5.0, 1.0));
+
+        // Centered, -1 A: 2048 - 1250 mV.
+        let sense = CurrentSense::bipolar(250, 5000, 4096);
+        assert_eq!(2048, adc_value(&sense, 0.0, 1.0));
+        assert_eq!(496, adc_value(&sense, -1.0, 0.5));
+        assert_eq!(0, adc_value(&sense, -2.0, 0.5));
+    }
+}
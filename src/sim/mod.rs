@@ -0,0 +1,350 @@
+//! Host side simulation of a stepper with encoder, to run `MotorControl`
+//! end-to-end without hardware. Needs std, enabled with the `sim` feature.
+
+pub mod hardware;
+pub mod plant;
+pub mod replay;
+pub mod scenario;
+
+use self::hardware::{SimEncoder, SimOutput};
+use self::plant::{Phase, Plant, StepperParameters};
+use crate::calibration::DEFAULT_TABLE_SIZE;
+use crate::config::{EncoderConfig, MotorConfig};
+use crate::current_control::{
+    CurrentControl, CurrentFilter, CurrentSense, DecayConfig, PIDControl, PulseConfig, SamplePhase,
+    SamplingConfig, ZeroConfig,
+};
+use crate::motor_control::{MotorControl, DWT_FREQ};
+use crate::position_control::{PositionInput, PositionTables};
+use crate::replay::{Header, Sample};
+use crate::switches::NoSwitches;
+use core::cell::{Ref, RefCell, RefMut};
+use std::rc::Rc;
+
+/// Frequency of the current loop, the ADC samples and the encoder updates.
+pub const CURRENT_LOOP_FREQUENCY: u32 = 20_000;
+
+pub type SimCurrentControl = CurrentControl<SimOutput>;
+pub type SimMotorControl = MotorControl<
+    SimCurrentControl,
+    SimCurrentControl,
+    SimEncoder,
+    NoSwitches,
+    DEFAULT_TABLE_SIZE,
+    Box<PositionTables>,
+>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimConfig {
+    pub motor: StepperParameters,
+    pub sense: CurrentSense,
+    pub zero: ZeroConfig,
+    pub filter: CurrentFilter,
+    /// The ADC samples in the middle of the PWM on time.
+    pub sampling: SamplingConfig,
+    pub decay: DecayConfig,
+    pub pulse: PulseConfig,
+    pub encoder: EncoderConfig,
+    /// PWM resolution, see `CurrentOutput::get_max_output_value`.
+    pub max_output_value: i32,
+    /// Current loop gains, see `PIDControl`.
+    pub current_p: i32,
+    pub current_i: i32,
+    pub current_d: i32,
+    /// Plant integration steps per current loop period.
+    pub substeps: u32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            motor: StepperParameters::default(),
+            sense: CurrentSense::unipolar(250, 0, 1 << 12),
+            zero: ZeroConfig::default(),
+            filter: CurrentFilter::None,
+            sampling: SamplingConfig::default(),
+            decay: DecayConfig::default(),
+            pulse: PulseConfig::default(),
+            encoder: EncoderConfig::default(),
+            max_output_value: 1000,
+            current_p: 2,
+            current_i: 2,
+            current_d: 0,
+            substeps: 10,
+        }
+    }
+}
+
+/// The plant and the control running together. Every current loop period
+/// the plant is integrated, the coil currents are sampled and the position
+/// is updated; `MotorControl::update` runs at the rate it requests.
+pub struct Simulation {
+    plant: Rc<RefCell<Plant>>,
+    motor_control: SimMotorControl,
+    config: SimConfig,
+    /// DWT cycles since the start.
+    cycles: u64,
+    next_update: u64,
+    last_sample: Sample,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let plant = Rc::new(RefCell::new(Plant::new(config.motor)));
+        let current_control = |phase| {
+            let output = SimOutput::new(plant.clone(), phase, config.max_output_value);
+            let mut current_control = CurrentControl::with_sense(output, config.sense);
+            current_control.set_zero_config(config.zero);
+            current_control.set_filter(config.filter);
+            current_control.set_sampling(config.sampling);
+            current_control.set_decay(config.decay);
+            current_control.set_pulse(config.pulse);
+            current_control.set_controller_p(config.current_p);
+            current_control.set_controller_i(config.current_i);
+            current_control.set_controller_d(config.current_d);
+            current_control
+        };
+        let coil_a = current_control(Phase::A);
+        let coil_b = current_control(Phase::B);
+        let encoder = SimEncoder::new(plant.clone(), config.encoder.pulses_per_rotation());
+
+        Self {
+            motor_control: MotorControl::new(
+                coil_a,
+                coil_b,
+                encoder,
+                NoSwitches,
+                MotorConfig {
+                    rotor_teeth: config.motor.rotor_teeth,
+                },
+                config.encoder,
+                // A static on the drive, owned by the simulation on the host.
+                Box::new(PositionTables::new()),
+            ),
+            plant,
+            config: SimConfig {
+                substeps: config.substeps.max(1),
+                ..config
+            },
+            cycles: 0,
+            next_update: 0,
+            last_sample: Sample::default(),
+        }
+    }
+
+    pub fn motor_control(&mut self) -> &mut SimMotorControl {
+        &mut self.motor_control
+    }
+    pub fn plant(&self) -> Ref<'_, Plant> {
+        self.plant.borrow()
+    }
+    /// To change the load or move the rotor.
+    pub fn plant_mut(&mut self) -> RefMut<'_, Plant> {
+        self.plant.borrow_mut()
+    }
+    pub fn config(&self) -> &SimConfig {
+        &self.config
+    }
+    /// Seconds since the start.
+    pub fn time(&self) -> f64 {
+        self.cycles as f64 / DWT_FREQ as f64
+    }
+
+    /// One current loop period.
+    pub fn step(&mut self) {
+        let period = 1.0 / CURRENT_LOOP_FREQUENCY as f64;
+        let (sample_a, sample_b) = {
+            let mut plant = self.plant.borrow_mut();
+            let substeps = self.config.substeps;
+            for _ in 0..substeps {
+                plant.step(period / substeps as f64);
+            }
+            let sample = |phase| {
+                hardware::adc_value(&self.config.sense, plant.current(phase), plant.duty(phase))
+            };
+            (sample(Phase::A), sample(Phase::B))
+        };
+
+        let motor_control = &mut self.motor_control;
+        motor_control
+            .coil_a()
+            .current_control()
+            .add_sample_at(sample_a, SamplePhase::Center);
+        motor_control
+            .coil_b()
+            .current_control()
+            .add_sample_at(sample_b, SamplePhase::Center);
+        motor_control.update_control_loop(1_000_000 / CURRENT_LOOP_FREQUENCY);
+        motor_control.handle_new_position();
+        self.last_sample = Sample {
+            adc_a: sample_a as u16,
+            adc_b: sample_b as u16,
+            position: motor_control
+                .position_control()
+                .position_input()
+                .get_position(),
+            output_a: motor_control.coil_a().current_control().output_value() as i16,
+            output_b: motor_control.coil_b().current_control().output_value() as i16,
+            updates: 0,
+            phase: SamplePhase::Center,
+        };
+
+        self.cycles += (DWT_FREQ as u32 / CURRENT_LOOP_FREQUENCY) as u64;
+        while self.cycles >= self.next_update {
+            self.next_update += motor_control.update() as u64;
+            self.last_sample.updates += 1;
+        }
+    }
+
+    /// The last current loop period, for a recording.
+    pub fn last_sample(&self) -> Sample {
+        self.last_sample
+    }
+    /// The setup, for a recording.
+    pub fn recording_header(&mut self) -> Header {
+        let motor_control = &mut self.motor_control;
+        Header {
+            sense: self.config.sense,
+            zero: self.config.zero,
+            offsets: [
+                motor_control.coil_a().current_control().sense().offset,
+                motor_control.coil_b().current_control().sense().offset,
+            ],
+            filter: self.config.filter,
+            sampling: self.config.sampling,
+            decay: self.config.decay,
+            pulse: self.config.pulse,
+            max_output_value: self.config.max_output_value,
+            p: self.config.current_p,
+            i: self.config.current_i,
+            d: self.config.current_d,
+            motor: MotorConfig {
+                rotor_teeth: self.config.motor.rotor_teeth,
+            },
+            encoder: self.config.encoder,
+        }
+    }
+
+    pub fn run(&mut self, seconds: f64) {
+        let end = self.time() + seconds;
+        while self.time() < end {
+            self.step();
+        }
+    }
+
+    /// Run until `done`, at most `timeout` seconds. False on a timeout.
+    pub fn run_until<F>(&mut self, timeout: f64, mut done: F) -> bool
+    where
+        F: FnMut(&mut Self) -> bool,
+    {
+        let end = self.time() + timeout;
+        while self.time() < end {
+            self.step();
+            if done(self) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soft_limits::{LimitMode, SoftLimits};
+    use core::f64::consts::PI;
+
+    #[test]
+    fn current_loop_follows_setpoint() {
+        // Locked rotor, no back-EMF.
+        let mut sim = Simulation::new(SimConfig {
+            motor: StepperParameters {
+                coulomb_friction: 1.0,
+                ..StepperParameters::default()
+            },
+            ..SimConfig::default()
+        });
+        sim.motor_control().set_current(800);
+        sim.motor_control().enable(true);
+        sim.motor_control().hold();
+        sim.run(0.02);
+
+        // Hold drives both coils with the full current.
+        let plant = sim.plant();
+        for phase in [Phase::A, Phase::B] {
+            let current = plant.current(phase);
+            assert!((current - 0.8).abs() < 0.05, "{:?} {}", phase, current);
+        }
+    }
+
+    #[test]
+    fn open_loop_rotation() {
+        let mut sim = Simulation::new(SimConfig::default());
+        sim.motor_control().set_current(800);
+        sim.motor_control().enable(true);
+        sim.motor_control().rotate(50);
+        sim.run(0.1);
+        let start = sim.plant().electrical_angle();
+
+        // One degree each 4000 cycles, 361 degrees per electrical cycle.
+        sim.run(0.2);
+        let moved = (sim.plant().electrical_angle() - start).to_degrees();
+        let expected = 0.2 * DWT_FREQ as f64 / 4000.0 * 360.0 / 361.0;
+        assert!((moved - expected).abs() < 20.0, "{}", moved);
+    }
+
+    #[test]
+    fn open_loop_rotation_stops_at_soft_limit() {
+        let mut sim = Simulation::new(SimConfig::default());
+        sim.motor_control().set_current(800);
+        sim.motor_control().enable(true);
+        sim.motor_control().set_soft_limits(SoftLimits {
+            deceleration: 20_000,
+            ..SoftLimits::new((-1200).into(), 1200.into(), LimitMode::Reject)
+        });
+        // 50 electrical cycles per second is one rotation, 2400 pulses.
+        sim.motor_control().rotate(50);
+        sim.run(1.0);
+
+        let position = sim
+            .motor_control()
+            .position_control()
+            .get_current_position()
+            .pulses();
+        assert!((1100..=1200).contains(&position), "{}", position);
+    }
+
+    #[test]
+    fn calibrate_and_move() {
+        let mut sim = Simulation::new(SimConfig::default());
+        sim.motor_control().set_current(800);
+        sim.motor_control().calibrate();
+        assert!(sim.run_until(20.0, |sim| sim
+            .motor_control()
+            .position_control()
+            .calibration_is_done()));
+
+        // Half a rotation further.
+        sim.motor_control().enable(true);
+        let start = sim.plant().angle();
+        let position = sim
+            .motor_control()
+            .position_control()
+            .get_current_position();
+        sim.motor_control()
+            .set_position(position.saturating_add(1200))
+            .unwrap();
+        sim.run(0.5);
+
+        let moved = sim.plant().angle() - start;
+        assert!((moved - PI).abs() < 0.02, "{}", moved);
+        let error = sim.motor_control().status().following_error;
+        // The small pull close to the setpoint does not beat detent and
+        // friction.
+        assert!(error.abs() <= 4, "{}", error);
+    }
+}
@@ -0,0 +1,213 @@
+use core::f64::consts::PI;
+
+/// Electrical and mechanical parameters of a two-phase hybrid stepper, in SI
+/// units. The default is a common 1.8 degree NEMA 17 motor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepperParameters {
+    pub rotor_teeth: u32,
+    /// Phase resistance, Ohm.
+    pub resistance: f64,
+    /// Phase inductance, H.
+    pub inductance: f64,
+    /// Torque per phase current, Nm/A. Also the back-EMF constant, Vs/rad.
+    pub torque_constant: f64,
+    /// Amplitude of the detent torque, 4 periods per electrical cycle, Nm.
+    pub detent_torque: f64,
+    /// Rotor and load inertia, kg m^2.
+    pub inertia: f64,
+    /// Viscous friction, Nm s/rad.
+    pub viscous_friction: f64,
+    /// Coulomb friction, Nm.
+    pub coulomb_friction: f64,
+    /// Bridge supply voltage, V.
+    pub supply_voltage: f64,
+}
+
+impl Default for StepperParameters {
+    fn default() -> Self {
+        Self {
+            rotor_teeth: 50,
+            resistance: 1.65,
+            inductance: 2.8e-3,
+            torque_constant: 0.26,
+            detent_torque: 0.015,
+            inertia: 6.8e-6,
+            viscous_friction: 1.0e-5,
+            coulomb_friction: 0.005,
+            supply_voltage: 12.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    A,
+    B,
+}
+
+/// State of the motor, integrated with `step`. The rotor settles where the
+/// phase currents are the sine and cosine of the electrical angle, as set by
+/// `Coil`: phase A pulls to 90 degrees with a positive current, phase B to 0.
+pub struct Plant {
+    parameters: StepperParameters,
+    /// Phase voltage as a fraction of the supply, -1 to 1.
+    duty: [f64; 2],
+    enabled: [bool; 2],
+    current: [f64; 2],
+    /// Mechanical rotor angle, rad.
+    angle: f64,
+    /// Rad/s.
+    velocity: f64,
+    /// Torque against the positive direction, Nm.
+    load_torque: f64,
+}
+
+impl Plant {
+    pub fn new(parameters: StepperParameters) -> Self {
+        Self {
+            parameters,
+            duty: [0.0; 2],
+            enabled: [false; 2],
+            current: [0.0; 2],
+            angle: 0.0,
+            velocity: 0.0,
+            load_torque: 0.0,
+        }
+    }
+    pub fn parameters(&self) -> &StepperParameters {
+        &self.parameters
+    }
+    pub fn set_duty(&mut self, phase: Phase, duty: f64) {
+        self.duty[phase as usize] = duty.clamp(-1.0, 1.0);
+    }
+    pub fn duty(&self, phase: Phase) -> f64 {
+        self.duty[phase as usize]
+    }
+    /// A disabled bridge is open, the current is gone right away.
+    pub fn set_enabled(&mut self, phase: Phase, enabled: bool) {
+        self.enabled[phase as usize] = enabled;
+        if !enabled {
+            self.current[phase as usize] = 0.0;
+        }
+    }
+    /// A.
+    pub fn current(&self, phase: Phase) -> f64 {
+        self.current[phase as usize]
+    }
+    /// Mechanical angle in rad, not wrapped.
+    pub fn angle(&self) -> f64 {
+        self.angle
+    }
+    pub fn set_angle(&mut self, angle: f64) {
+        self.angle = angle;
+    }
+    /// Rad/s.
+    pub fn velocity(&self) -> f64 {
+        self.velocity
+    }
+    pub fn set_load_torque(&mut self, torque: f64) {
+        self.load_torque = torque;
+    }
+    /// Electrical angle in rad, not wrapped.
+    pub fn electrical_angle(&self) -> f64 {
+        self.angle * self.parameters.rotor_teeth as f64
+    }
+
+    /// Torque of the coils and the detent, without friction and load.
+    pub fn motor_torque(&self) -> f64 {
+        let p = &self.parameters;
+        let (sin, cos) = self.electrical_angle().sin_cos();
+        let [current_a, current_b] = self.current;
+        p.torque_constant * (current_a * cos - current_b * sin)
+            - p.detent_torque * (4.0 * self.electrical_angle()).sin()
+    }
+
+    /// Advance `dt` seconds, explicit Euler. Keep `dt` well below L/R.
+    pub fn step(&mut self, dt: f64) {
+        let p = self.parameters;
+        let (sin, cos) = self.electrical_angle().sin_cos();
+        let back_emf = [
+            p.torque_constant * self.velocity * cos,
+            -p.torque_constant * self.velocity * sin,
+        ];
+        for (phase, back_emf) in back_emf.iter().enumerate() {
+            if self.enabled[phase] {
+                let voltage = self.duty[phase] * p.supply_voltage;
+                let resistive = p.resistance * self.current[phase];
+                self.current[phase] += (voltage - resistive - back_emf) / p.inductance * dt;
+            }
+        }
+
+        let torque = self.motor_torque() - self.load_torque - p.viscous_friction * self.velocity;
+        if self.velocity == 0.0 && torque.abs() <= p.coulomb_friction {
+            // Sticking.
+            return;
+        }
+        let friction = if self.velocity != 0.0 {
+            p.coulomb_friction * self.velocity.signum()
+        } else {
+            p.coulomb_friction * torque.signum()
+        };
+        let velocity = self.velocity + (torque - friction) / p.inertia * dt;
+        // Friction stops the rotor, it does not reverse it.
+        self.velocity = if velocity * self.velocity < 0.0 {
+            0.0
+        } else {
+            velocity
+        };
+        self.angle += self.velocity * dt;
+    }
+
+    /// Rotations since the start.
+    pub fn rotations(&self) -> f64 {
+        self.angle / (2.0 * PI)
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(plant: &mut Plant, seconds: f64) {
+        for _ in 0..(seconds / 1e-6) as usize {
+            plant.step(1e-6);
+        }
+    }
+
+    #[test]
+    fn current_rises_with_time_constant() {
+        let mut plant = Plant::new(StepperParameters {
+            coulomb_friction: 1.0,
+            ..StepperParameters::default()
+        });
+        plant.set_enabled(Phase::A, true);
+        plant.set_duty(Phase::A, 0.5);
+        let p = *plant.parameters();
+        let tau = p.inductance / p.resistance;
+        run(&mut plant, tau);
+
+        let end = 0.5 * p.supply_voltage / p.resistance;
+        let expected = end * (1.0 - (-1.0_f64).exp());
+        assert!((plant.current(Phase::A) - expected).abs() < 0.01 * end);
+        assert_eq!(0.0, plant.velocity());
+    }
+
+    #[test]
+    fn rotor_follows_phase_a() {
+        let mut plant = Plant::new(StepperParameters::default());
+        plant.set_enabled(Phase::A, true);
+        plant.set_duty(Phase::A, 0.2);
+        run(&mut plant, 0.2);
+
+        // Settles a full step further, 90 electrical degrees.
+        let electrical = plant.electrical_angle().to_degrees();
+        assert!((electrical - 90.0).abs() < 2.0, "{}", electrical);
+
+        plant.set_enabled(Phase::A, false);
+        assert_eq!(0.0, plant.current(Phase::A));
+    }
+}
@@ -0,0 +1,385 @@
+use super::scenario::execute;
+use crate::calibration::DEFAULT_TABLE_SIZE;
+use crate::current_control::{CurrentControl, CurrentOutput, PIDControl};
+use crate::motor_control::MotorControl;
+use crate::position_control::{Direction, PositionInput, PositionTables};
+use crate::replay::{Entries, Entry, FormatError, Header, Sample};
+use crate::serial_commands::{Command, Response};
+use crate::switches::NoSwitches;
+use core::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+/// The outputs are compared through `CurrentControl::output_value`.
+pub struct ReplayOutput {
+    max_value: i32,
+}
+
+impl CurrentOutput for ReplayOutput {
+    fn set_output_value(&mut self, _value: i32) {}
+    fn enable(&mut self, _enable: bool) {}
+    fn get_max_output_value(&mut self) -> i32 {
+        self.max_value
+    }
+}
+
+/// Gives the recorded positions. A reset reads 0 until the next sample,
+/// the recording already has the positions after the reset.
+pub struct ReplayEncoder {
+    recorded: Rc<Cell<i32>>,
+    position: i32,
+}
+
+impl PositionInput for ReplayEncoder {
+    fn update(&mut self) {
+        self.position = self.recorded.get();
+    }
+    fn reset(&mut self) {
+        self.recorded.set(0);
+        self.position = 0;
+    }
+    fn get_position(&self) -> i32 {
+        self.position
+    }
+    fn get_direction(&self) -> Direction {
+        Direction::Unknown(0)
+    }
+}
+
+type ReplayMotorControl = MotorControl<
+    CurrentControl<ReplayOutput>,
+    CurrentControl<ReplayOutput>,
+    ReplayEncoder,
+    NoSwitches,
+    DEFAULT_TABLE_SIZE,
+    Box<PositionTables>,
+>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayError {
+    Format(FormatError),
+    /// A sample or command before the header.
+    NoHeader,
+    Command(String),
+}
+
+impl From<FormatError> for ReplayError {
+    fn from(error: FormatError) -> Self {
+        ReplayError::Format(error)
+    }
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Format(error) => write!(f, "bad entry at byte {}", error.offset),
+            ReplayError::NoHeader => write!(f, "no header"),
+            ReplayError::Command(command) => write!(f, "unknown command '{}'", command),
+        }
+    }
+}
+
+/// A sample where the replay gave other outputs than the drive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mismatch {
+    /// Starts at 0.
+    pub sample: usize,
+    pub recorded: (i32, i32),
+    pub replayed: (i32, i32),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub samples: usize,
+    pub mismatches: usize,
+    pub first_mismatch: Option<Mismatch>,
+    /// Largest output difference.
+    pub max_difference: i32,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} samples, {} mismatches, max difference {}",
+            self.samples, self.mismatches, self.max_difference
+        )?;
+        if let Some(mismatch) = self.first_mismatch {
+            write!(
+                f,
+                ", first at sample {}: recorded {:?} replayed {:?}",
+                mismatch.sample, mismatch.recorded, mismatch.replayed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a recording through the control loops, see `replay`.
+pub struct Replay {
+    header: Header,
+    motor_control: ReplayMotorControl,
+    position: Rc<Cell<i32>>,
+    report: ReplayReport,
+}
+
+impl Replay {
+    pub fn new(header: Header) -> Self {
+        let current_control = |offset| {
+            let output = ReplayOutput {
+                max_value: header.max_output_value,
+            };
+            let mut current_control = CurrentControl::with_sense(output, header.sense);
+            current_control.set_measured_offset(offset);
+            current_control.set_zero_config(header.zero);
+            current_control.set_filter(header.filter);
+            current_control.set_sampling(header.sampling);
+            current_control.set_decay(header.decay);
+            current_control.set_pulse(header.pulse);
+            current_control.set_controller_p(header.p);
+            current_control.set_controller_i(header.i);
+            current_control.set_controller_d(header.d);
+            current_control
+        };
+        let position = Rc::new(Cell::new(0));
+        let encoder = ReplayEncoder {
+            recorded: position.clone(),
+            position: 0,
+        };
+        Self {
+            header,
+            motor_control: MotorControl::new(
+                current_control(header.offsets[0]),
+                current_control(header.offsets[1]),
+                encoder,
+                NoSwitches,
+                header.motor,
+                header.encoder,
+                Box::new(PositionTables::new()),
+            ),
+            position,
+            report: ReplayReport::default(),
+        }
+    }
+
+    pub fn motor_control(&mut self) -> &mut ReplayMotorControl {
+        &mut self.motor_control
+    }
+    pub fn report(&self) -> &ReplayReport {
+        &self.report
+    }
+
+    /// Apply a recorded command, with the answer the drive gave.
+    pub fn command(&mut self, command: &Command) -> Response {
+        let pulses_per_rotation = self.header.encoder.pulses_per_rotation();
+        execute(&mut self.motor_control, command, pulses_per_rotation)
+    }
+
+    /// One current loop period, in the order of the drive.
+    pub fn sample(&mut self, sample: &Sample) {
+        let motor_control = &mut self.motor_control;
+        motor_control
+            .coil_a()
+            .current_control()
+            .add_sample_at(sample.adc_a as u32, sample.phase);
+        motor_control
+            .coil_b()
+            .current_control()
+            .add_sample_at(sample.adc_b as u32, sample.phase);
+        motor_control.update_control_loop(0);
+        self.position.set(sample.position);
+        motor_control.handle_new_position();
+
+        let replayed = (
+            motor_control.coil_a().current_control().output_value(),
+            motor_control.coil_b().current_control().output_value(),
+        );
+        let recorded = (sample.output_a as i32, sample.output_b as i32);
+        let difference = (replayed.0 - recorded.0)
+            .abs()
+            .max((replayed.1 - recorded.1).abs());
+        let report = &mut self.report;
+        if difference != 0 {
+            report.mismatches += 1;
+            report.max_difference = report.max_difference.max(difference);
+            report.first_mismatch.get_or_insert(Mismatch {
+                sample: report.samples,
+                recorded,
+                replayed,
+            });
+        }
+        report.samples += 1;
+
+        for _ in 0..sample.updates {
+            motor_control.update();
+        }
+    }
+}
+
+/// Replay a whole recording, which starts with the header.
+pub fn replay(data: &[u8]) -> Result<ReplayReport, ReplayError> {
+    let mut replay: Option<Replay> = None;
+    for entry in Entries::new(data) {
+        match (entry?, &mut replay) {
+            (Entry::Header(header), _) => replay = Some(Replay::new(header)),
+            (_, None) => return Err(ReplayError::NoHeader),
+            (Entry::Command(text), Some(replay)) => {
+                let command = Command::parse_from(text.split_whitespace())
+                    .ok_or_else(|| ReplayError::Command(text.to_string()))?;
+                replay.command(&command);
+            }
+            (Entry::Sample(sample), Some(replay)) => replay.sample(&sample),
+        }
+    }
+    Ok(replay.map(|replay| replay.report).unwrap_or_default())
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::current_control::{
+        CurrentFilter, CurrentSense, PulseConfig, SamplingConfig, ZeroConfig,
+    };
+    use crate::multi_turn::MultiTurnPosition;
+    use crate::replay::Recorder;
+    use crate::serial_commands::CommandError;
+    use crate::sim::{SimConfig, Simulation};
+
+    /// Record the simulation running `commands`, each after 0.1 s.
+    fn record(config: SimConfig, buffer: &mut [u8], commands: &[&str]) -> usize {
+        let mut sim = Simulation::new(config);
+        let mut recorder = Recorder::new(buffer);
+        assert!(recorder.header(&sim.recording_header()));
+        for text in commands {
+            let command = Command::parse_from(text.split_whitespace()).unwrap();
+            assert!(recorder.command(text));
+            sim.execute(&command);
+            for _ in 0..2000 {
+                sim.step();
+                assert!(recorder.sample(&sim.last_sample()));
+            }
+        }
+        recorder.data().len()
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let mut buffer = vec![0; 1 << 20];
+        // The filter and the gains are part of the recorded setup.
+        let config = SimConfig {
+            filter: CurrentFilter::Boxcar { length: 4 },
+            current_d: 1,
+            ..SimConfig::default()
+        };
+        let length = record(config, &mut buffer, &["c 600", "e", "r 20", "mp 3"]);
+
+        let report = replay(&buffer[..length]).unwrap();
+        assert_eq!(8000, report.samples);
+        assert_eq!(0, report.mismatches, "{}", report);
+    }
+
+    #[test]
+    fn replay_output_stage_setup() {
+        let mut buffer = vec![0; 1 << 20];
+        // Samples at a low duty are discarded, the current is estimated.
+        // The output is corrected for the dead time and short pulses.
+        let config = SimConfig {
+            sampling: SamplingConfig {
+                settle_time: 100,
+                acquisition_time: 50,
+            },
+            pulse: PulseConfig {
+                dead_time: 20,
+                dead_time_band: 50,
+                min_pulse: 30,
+            },
+            ..SimConfig::default()
+        };
+        let length = record(config, &mut buffer, &["c 600", "e", "r 20", "mp 3"]);
+
+        let report = replay(&buffer[..length]).unwrap();
+        assert_eq!(8000, report.samples);
+        assert_eq!(0, report.mismatches, "{}", report);
+    }
+
+    #[test]
+    fn replay_zeroing() {
+        let mut buffer = vec![0; 1 << 20];
+        // The zeroing on the enable is replayed from the recorded samples,
+        // long enough to keep the output off for the first current setpoint.
+        let config = SimConfig {
+            sense: CurrentSense::bipolar(250, 5000, 1 << 12),
+            zero: ZeroConfig {
+                samples: 1000,
+                on_enable: true,
+                ..ZeroConfig::default()
+            },
+            ..SimConfig::default()
+        };
+        let length = record(config, &mut buffer, &["c 600", "e", "r 20"]);
+        let report = replay(&buffer[..length]).unwrap();
+        assert_eq!(0, report.mismatches, "{}", report);
+
+        // An offset measured before the recording.
+        let header = Header {
+            offsets: [2040, 2061],
+            ..Simulation::new(config).recording_header()
+        };
+        let mut replay = Replay::new(header);
+        let current_control = replay.motor_control().coil_b().current_control();
+        assert_eq!(2061, current_control.sense().offset);
+    }
+
+    #[test]
+    fn replay_finds_changes() {
+        let mut buffer = vec![0; 1 << 20];
+        let length = record(SimConfig::default(), &mut buffer, &["c 600", "e", "h"]);
+
+        // A different gain than on the drive, from the first driven sample.
+        let mut replay = Replay::new(Simulation::new(SimConfig::default()).recording_header());
+        replay.motor_control().set_controller_p(5);
+        for entry in Entries::new(&buffer[..length]).skip(1) {
+            match entry.unwrap() {
+                Entry::Command(text) => {
+                    replay.command(&Command::parse_from(text.split_whitespace()).unwrap());
+                }
+                Entry::Sample(sample) => replay.sample(&sample),
+                Entry::Header(_) => unreachable!(),
+            }
+        }
+        let report = replay.report();
+        assert!(report.mismatches > 0);
+        assert!(report.first_mismatch.unwrap().sample >= 2000);
+    }
+
+    #[test]
+    fn replay_rejects_pulses_outside_the_turn() {
+        let mut replay = Replay::new(Simulation::new(SimConfig::default()).recording_header());
+        let command = Command::parse_from("pt 1 -100".split_whitespace()).unwrap();
+        assert_eq!(
+            Response::Error(CommandError::PulsesOutOfRange(-100)),
+            replay.command(&command)
+        );
+        let setpoint = replay
+            .motor_control()
+            .position_control()
+            .get_position_setpoint();
+        assert_eq!(MultiTurnPosition::ZERO, setpoint);
+    }
+
+    #[test]
+    fn replay_errors() {
+        let mut buffer = [0; 16];
+        let mut recorder = Recorder::new(&mut buffer);
+        recorder.command("e");
+        assert_eq!(Err(ReplayError::NoHeader), replay(recorder.data()));
+        assert_eq!(
+            Err(ReplayError::Format(FormatError { offset: 0 })),
+            replay(b"Z")
+        );
+    }
+}
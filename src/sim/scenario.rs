@@ -0,0 +1,543 @@
+use super::plant::Phase;
+use super::Simulation;
+use crate::current_control::{CurrentDevice, PIDControl};
+use crate::motor_control::{MotorControl, PositionControlled};
+use crate::multi_turn::MultiTurnPosition;
+use crate::position_control::{PositionInput, TableStorage};
+use crate::serial_commands::{Command, CommandError, Response};
+use crate::soft_limits::SoftLimits;
+use crate::switches::{Side, SwitchInput};
+use crate::waveform::Waveform;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A serial command, sent `time_ms` after the start.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub time_ms: u32,
+    pub command: Command,
+}
+
+/// Checked on the move started by the last position command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Check {
+    /// Pulses past the target.
+    Overshoot { max: i64 },
+    /// Within `band` pulses of the target at most `time_ms` after the
+    /// command, and staying there.
+    Settle { time_ms: u32, band: i64 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// Starts at 1.
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: can not parse '{}'", self.line, self.text)
+    }
+}
+
+/// Commands with timestamps and the checks on the result, from a text with
+/// one entry per line:
+///
+/// ```text
+/// # Comment
+/// 0 c 800
+/// 0 cal
+/// 11000 e
+/// 11000 p 1200
+/// check overshoot 20
+/// check settle 300 5
+/// end 12000
+/// ```
+///
+/// Without an `end` the scenario stops one second after the last command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+    pub checks: Vec<Check>,
+    pub duration_ms: u32,
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut scenario = Scenario::default();
+        let mut end = None;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || ParseError {
+                line: index + 1,
+                text: line.to_string(),
+            };
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("check") => scenario
+                    .checks
+                    .push(Self::parse_check(parts).ok_or_else(error)?),
+                Some("end") => end = Some(Self::value(parts.next()).ok_or_else(error)?),
+                time_ms => {
+                    let time_ms = Self::value(time_ms).ok_or_else(error)?;
+                    let command = Command::parse_from(parts).ok_or_else(error)?;
+                    scenario.steps.push(Step { time_ms, command });
+                }
+            }
+        }
+        scenario.steps.sort_by_key(|step| step.time_ms);
+        let last = scenario.steps.last().map_or(0, |step| step.time_ms);
+        scenario.duration_ms = end.unwrap_or(last + 1000);
+        Ok(scenario)
+    }
+
+    fn parse_check<'a, I>(mut parts: I) -> Option<Check>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        match parts.next()? {
+            "overshoot" => Some(Check::Overshoot {
+                max: Self::value(parts.next())?,
+            }),
+            "settle" => Some(Check::Settle {
+                time_ms: Self::value(parts.next())?,
+                band: Self::value(parts.next())?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn value<T: std::str::FromStr>(part: Option<&str>) -> Option<T> {
+        part?.parse().ok()
+    }
+}
+
+/// The state after one current loop period.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    /// s.
+    pub time: f64,
+    /// Phase currents of the motor, mA.
+    pub current_a: f64,
+    pub current_b: f64,
+    /// As measured by `CurrentControl`, mA.
+    pub measured_a: i32,
+    pub measured_b: i32,
+    /// -1 to 1.
+    pub duty_a: f64,
+    pub duty_b: f64,
+    /// Commanded electrical angle, degrees.
+    pub commanded_angle: i32,
+    /// Electrical angle of the rotor, degrees 0 to 360.
+    pub rotor_angle: f64,
+    pub position: i64,
+    pub setpoint: i64,
+}
+
+impl Record {
+    pub const CSV_HEADER: &'static str = "time,current_a,current_b,measured_a,measured_b,\
+        duty_a,duty_b,commanded_angle,rotor_angle,position,setpoint";
+}
+
+/// A CSV line, without the line end.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.5},{:.1},{:.1},{},{},{:.3},{:.3},{},{:.1},{},{}",
+            self.time,
+            self.current_a,
+            self.current_b,
+            self.measured_a,
+            self.measured_b,
+            self.duty_a,
+            self.duty_b,
+            self.commanded_angle,
+            self.rotor_angle,
+            self.position,
+            self.setpoint
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CheckResult {
+    pub check: Check,
+    /// Pulses for an overshoot, ms for a settle time. `None` when there was
+    /// no move, or it did not settle.
+    pub value: Option<i64>,
+    pub passed: bool,
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = if self.passed { "pass" } else { "FAIL" };
+        let value = match self.value {
+            Some(value) => value.to_string(),
+            None => "-".to_string(),
+        };
+        match self.check {
+            Check::Overshoot { max } => {
+                write!(f, "{} overshoot {} (max {})", verdict, value, max)
+            }
+            Check::Settle { time_ms, band } => write!(
+                f,
+                "{} settle {} ms (max {} ms within {})",
+                verdict, value, time_ms, band
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Answer to each step, in order.
+    pub responses: Vec<(u32, Response)>,
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+}
+
+/// The last move, for the checks.
+struct Move {
+    time: f64,
+    start: i64,
+    target: i64,
+    overshoot: i64,
+    /// Last time outside of each settle band.
+    unsettled: Vec<f64>,
+}
+
+/// Apply a serial command, as the firmware does.
+pub fn execute<T1, T2, Inp, Sw, const N: usize, Tables>(
+    motor_control: &mut MotorControl<T1, T2, Inp, Sw, N, Tables>,
+    command: &Command,
+    pulses_per_rotation: u32,
+) -> Response
+where
+    T1: CurrentDevice + PIDControl,
+    T2: CurrentDevice + PIDControl,
+    Inp: PositionInput,
+    Sw: SwitchInput,
+    Tables: TableStorage<N>,
+{
+    match *command {
+        Command::Enable => motor_control.enable(true),
+        Command::Disable => motor_control.enable(false),
+        Command::DisableNow => motor_control.disable_now(),
+        Command::QuickStop => motor_control.quick_stop(),
+        Command::Rotate { speed } => motor_control.rotate(speed),
+        Command::Hold => motor_control.hold(),
+        Command::Cur { current } => motor_control.set_current(current),
+        Command::Position { position } => {
+            let position = MultiTurnPosition::from_pulses(position as i64);
+            if let Err(violation) = motor_control.set_position(position) {
+                return violation.into();
+            }
+        }
+        Command::Speed { speed } => motor_control.set_speed(speed),
+        Command::PositionAndSpeed { position, speed } => {
+            motor_control.set_speed(speed);
+            let position = MultiTurnPosition::from_pulses(position as i64);
+            if let Err(violation) = motor_control.set_position(position) {
+                return violation.into();
+            }
+        }
+        Command::PositionTurns { turns, pulses } => {
+            let pulses = match u32::try_from(pulses) {
+                Ok(pulses) if pulses < pulses_per_rotation => pulses,
+                _ => return CommandError::PulsesOutOfRange(pulses).into(),
+            };
+            let position = MultiTurnPosition::from_turns(turns, pulses, pulses_per_rotation);
+            if let Err(violation) = motor_control.set_position(position) {
+                return violation.into();
+            }
+        }
+        Command::HomeIndex { speed } => motor_control.home_to_index(speed),
+        Command::Home {
+            direction,
+            speed,
+            offset,
+        } => motor_control.home(Side::from_sign(direction), speed, offset as i64),
+        Command::SoftLimits { min, max } => motor_control.set_soft_limits(SoftLimits {
+            min: MultiTurnPosition::from_pulses(min as i64),
+            max: MultiTurnPosition::from_pulses(max as i64),
+            ..*motor_control.soft_limits()
+        }),
+        Command::ResetEstop => {
+            if !motor_control.reset_estop() {
+                return CommandError::EstopActive.into();
+            }
+        }
+        Command::ResetFault => {
+            motor_control.stall_detector().reset_fault();
+            motor_control.reset_sense_fault();
+        }
+        Command::Status => return Response::Status(motor_control.status()),
+        Command::P(value) => motor_control.set_controller_p(value),
+        Command::I(value) => motor_control.set_controller_i(value),
+        Command::D(value) => motor_control.set_controller_d(value),
+        Command::Calibrate => motor_control.calibrate(),
+        Command::MeasureCogging { velocity } => motor_control.measure_cogging(velocity),
+        Command::SelectWaveform { waveform: 1 } => {
+            if !motor_control.use_harmonic_waveform() {
+                return CommandError::NotCalibrated.into();
+            }
+        }
+        Command::SelectWaveform { .. } => motor_control.set_waveform(Waveform::Sine),
+        Command::WaveformPoint { index, value } => {
+            motor_control.set_waveform_point(index as usize, value)
+        }
+        Command::ShowCalData => {}
+        Command::ForceDuty(duty) => motor_control.force_duty(duty),
+    }
+    Response::Ok
+}
+
+impl Simulation {
+    /// Apply a serial command to the simulated drive.
+    pub fn execute(&mut self, command: &Command) -> Response {
+        let pulses_per_rotation = self.config.encoder.pulses_per_rotation();
+        execute(&mut self.motor_control, command, pulses_per_rotation)
+    }
+
+    pub fn record(&mut self) -> Record {
+        let time = self.time();
+        let plant = self.plant.borrow();
+        let motor_control = &mut self.motor_control;
+        let status = motor_control.status();
+        Record {
+            time,
+            current_a: plant.current(Phase::A) * 1000.0,
+            current_b: plant.current(Phase::B) * 1000.0,
+            measured_a: motor_control.coil_a().current_control().current(),
+            measured_b: motor_control.coil_b().current_control().current(),
+            duty_a: plant.duty(Phase::A),
+            duty_b: plant.duty(Phase::B),
+            commanded_angle: motor_control.get_angle(),
+            rotor_angle: plant.electrical_angle().to_degrees().rem_euclid(360.0),
+            position: status.position.pulses(),
+            setpoint: motor_control
+                .position_control()
+                .get_position_setpoint()
+                .pulses(),
+        }
+    }
+
+    /// Run the `scenario`, `log` gets a record every current loop period.
+    pub fn run_scenario<F>(&mut self, scenario: &Scenario, mut log: F) -> Report
+    where
+        F: FnMut(&Record),
+    {
+        let mut report = Report::default();
+        let mut steps = scenario.steps.iter().peekable();
+        let mut last_move: Option<Move> = None;
+        let end = self.time() + scenario.duration_ms as f64 / 1000.0;
+        let start = self.time();
+
+        while self.time() < end {
+            let now_ms = ((self.time() - start) * 1000.0) as u32;
+            while let Some(step) = steps.next_if(|step| step.time_ms <= now_ms) {
+                let position = self.motor_control.status().position.pulses();
+                let response = self.execute(&step.command);
+                if Self::is_move(&step.command) && response == Response::Ok {
+                    last_move = Some(Move {
+                        time: self.time(),
+                        start: position,
+                        target: self
+                            .motor_control
+                            .position_control()
+                            .get_position_setpoint()
+                            .pulses(),
+                        overshoot: 0,
+                        unsettled: vec![self.time(); scenario.checks.len()],
+                    });
+                }
+                report.responses.push((step.time_ms, response));
+            }
+
+            self.step();
+            let record = self.record();
+            if let Some(last_move) = &mut last_move {
+                let direction = (last_move.target - last_move.start).signum();
+                let past = (record.position - last_move.target) * direction;
+                last_move.overshoot = last_move.overshoot.max(past);
+                for (check, unsettled) in scenario.checks.iter().zip(&mut last_move.unsettled) {
+                    if let Check::Settle { band, .. } = check {
+                        if (record.position - last_move.target).abs() > *band {
+                            *unsettled = record.time;
+                        }
+                    }
+                }
+            }
+            log(&record);
+        }
+
+        report.results = scenario
+            .checks
+            .iter()
+            .enumerate()
+            .map(|(index, check)| Self::evaluate(*check, last_move.as_ref(), index, end))
+            .collect();
+        report
+    }
+
+    fn is_move(command: &Command) -> bool {
+        matches!(
+            command,
+            Command::Position { .. }
+                | Command::PositionAndSpeed { .. }
+                | Command::PositionTurns { .. }
+        )
+    }
+
+    fn evaluate(check: Check, last_move: Option<&Move>, index: usize, end: f64) -> CheckResult {
+        let value = last_move.and_then(|last_move| match check {
+            Check::Overshoot { .. } => Some(last_move.overshoot),
+            Check::Settle { .. } => {
+                let unsettled = last_move.unsettled[index];
+                // Still moving at the end.
+                if end - unsettled < 0.001 {
+                    None
+                } else {
+                    Some(((unsettled - last_move.time) * 1000.0).round() as i64)
+                }
+            }
+        });
+        let passed = match (check, value) {
+            (Check::Overshoot { max }, Some(value)) => value <= max,
+            (Check::Settle { time_ms, .. }, Some(value)) => value <= time_ms as i64,
+            _ => false,
+        };
+        CheckResult {
+            check,
+            value,
+            passed,
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimConfig;
+
+    #[test]
+    fn parse_scenario() {
+        let scenario = Scenario::parse(
+            "# Move
+            100 p 1200
+            0 c 800
+
+            check overshoot 20
+            check settle 300 5",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                Step {
+                    time_ms: 0,
+                    command: Command::Cur { current: 800 }
+                },
+                Step {
+                    time_ms: 100,
+                    command: Command::Position { position: 1200 }
+                },
+            ],
+            scenario.steps
+        );
+        assert_eq!(
+            vec![
+                Check::Overshoot { max: 20 },
+                Check::Settle {
+                    time_ms: 300,
+                    band: 5
+                }
+            ],
+            scenario.checks
+        );
+        assert_eq!(1100, scenario.duration_ms);
+
+        let error = Scenario::parse("0 e\nend\n").unwrap_err();
+        assert_eq!(2, error.line);
+        assert!(Scenario::parse("0 jump 5").is_err());
+        assert!(Scenario::parse("check overshoot").is_err());
+    }
+
+    #[test]
+    fn position_turns_within_one_turn() {
+        let mut sim = Simulation::new(SimConfig::default());
+        let pulses_per_rotation = SimConfig::default().encoder.pulses_per_rotation() as i32;
+
+        for pulses in [-100, pulses_per_rotation] {
+            assert_eq!(
+                Response::Error(CommandError::PulsesOutOfRange(pulses)),
+                sim.execute(&Command::PositionTurns { turns: 1, pulses })
+            );
+            assert_eq!(0, sim.record().setpoint);
+        }
+        let command = Command::PositionTurns {
+            turns: -1,
+            pulses: pulses_per_rotation - 1,
+        };
+        assert_eq!(Response::Ok, sim.execute(&command));
+        assert_eq!(-1, sim.record().setpoint);
+    }
+
+    #[test]
+    fn harmonic_waveform_needs_calibration() {
+        let mut sim = Simulation::new(SimConfig::default());
+        assert_eq!(
+            Response::Error(CommandError::NotCalibrated),
+            sim.execute(&Command::SelectWaveform { waveform: 1 })
+        );
+        assert_eq!(Response::Ok, sim.execute(&Command::ResetEstop));
+    }
+
+    #[test]
+    fn calibrated_move() {
+        let scenario = Scenario::parse(
+            "0 c 800
+            0 cal
+            11000 e
+            11000 status
+            11000 p 1200
+            check overshoot 50
+            check settle 400 5
+            check settle 1 0
+            end 12000",
+        )
+        .unwrap();
+        let mut sim = Simulation::new(SimConfig::default());
+        let mut records = 0;
+        let report = sim.run_scenario(&scenario, |_| records += 1);
+
+        assert_eq!(12000 * 20, records);
+        assert_eq!(5, report.responses.len());
+        assert!(matches!(
+            report.responses[3],
+            (11000, Response::Status(status)) if status.enabled
+        ));
+        let results = &report.results;
+        assert!(results[0].passed && results[1].passed, "{:?}", results);
+        assert!(!results[2].passed);
+        assert!(!report.passed());
+
+        let record = sim.record();
+        assert_eq!(1200, record.setpoint);
+        assert_eq!(11, record.to_string().split(',').count());
+        assert_eq!(11, Record::CSV_HEADER.split(',').count());
+    }
+}
@@ -0,0 +1,59 @@
+/// Electrical angle as a fraction of a full turn, 65536 is 360 degrees.
+/// One microstep of 1/256 full step is 64.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ElectricalAngle(pub u16);
+
+impl ElectricalAngle {
+    pub const ZERO: Self = ElectricalAngle(0);
+    /// 90 degrees, one full step.
+    pub const QUARTER: Self = ElectricalAngle(1 << 14);
+
+    pub fn from_degrees(degrees: i32) -> Self {
+        Self::from_fixed_point(degrees, 0)
+    }
+
+    /// From degrees with `fraction_bits` bits below the whole degree.
+    pub fn from_fixed_point(angle: i32, fraction_bits: u32) -> Self {
+        let full_circle = 360_i64 << fraction_bits;
+        let angle = (angle as i64).rem_euclid(full_circle);
+        ElectricalAngle(((angle << 16) / full_circle) as u16)
+    }
+
+    /// Rounded to whole degrees, 0 to 359.
+    pub fn degrees(self) -> i32 {
+        ((self.0 as i32 * 360 + (1 << 15)) >> 16) % 360
+    }
+
+    pub fn wrapping_add(self, other: Self) -> Self {
+        ElectricalAngle(self.0.wrapping_add(other.0))
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(ElectricalAngle::QUARTER, ElectricalAngle::from_degrees(90));
+        assert_eq!(
+            ElectricalAngle::QUARTER,
+            ElectricalAngle::from_degrees(-270)
+        );
+        assert_eq!(ElectricalAngle::ZERO, ElectricalAngle::from_degrees(360));
+        assert_eq!(
+            ElectricalAngle(1 << 13),
+            ElectricalAngle::from_fixed_point(45 << 8, 8)
+        );
+        assert_eq!(359, ElectricalAngle::from_degrees(-1).degrees());
+        assert_eq!(0, ElectricalAngle(65535).degrees());
+        assert_eq!(
+            ElectricalAngle::from_degrees(10),
+            ElectricalAngle::from_degrees(280).wrapping_add(ElectricalAngle::QUARTER)
+        );
+    }
+}
@@ -0,0 +1,6 @@
+//! Table parameters, shared with `build.rs` which generates the tables.
+
+pub const SAMPLE_POINTS: u32 = 360;
+pub const SCALING_FACTOR: u32 = 65535; // 16bits
+/// Entries of the fine table, interpolated in between.
+pub const FINE_SAMPLE_POINTS: u32 = 1024;
@@ -1,13 +1,55 @@
-use super::lookup_table::SIN_LOOKUP_TABLE;
-use super::SCALING_FACTOR;
+use super::angle::ElectricalAngle;
+use super::lookup_table::{FINE_SIN_LOOKUP_TABLE, SIN_LOOKUP_TABLE};
+use super::{FINE_SAMPLE_POINTS, SAMPLE_POINTS, SCALING_FACTOR};
+
+/// Bits of the angle between two fine table entries.
+const INTERPOLATION_BITS: u32 = 16 - FINE_SAMPLE_POINTS.trailing_zeros();
 
 #[allow(dead_code)]
 pub fn get_sine(degree: u32, value: i32) -> i32 {
     let degree = degree % 360;
-    let value = value * SIN_LOOKUP_TABLE[degree as usize];
+    let value = value * sample(&SIN_LOOKUP_TABLE, degree as usize, SAMPLE_POINTS as usize);
     value / SCALING_FACTOR as i32
 }
 
+/// Sine of `angle` times `value`, interpolated in the fine table.
+pub fn sin(angle: ElectricalAngle, value: i32) -> i32 {
+    (value as i64 * fine_sine(angle) as i64 / SCALING_FACTOR as i64) as i32
+}
+
+/// Sine and cosine of `angle` times `value`, for the two coils.
+pub fn sin_cos(angle: ElectricalAngle, value: i32) -> (i32, i32) {
+    (sin(angle, value), sin(angle.wrapping_add(ElectricalAngle::QUARTER), value))
+}
+
+fn fine_sine(angle: ElectricalAngle) -> i32 {
+    let index = (angle.0 >> INTERPOLATION_BITS) as usize;
+    let fraction = (angle.0 & ((1 << INTERPOLATION_BITS) - 1)) as i32;
+    let points = FINE_SAMPLE_POINTS as usize;
+    let from = sample(&FINE_SIN_LOOKUP_TABLE, index, points);
+    let to = sample(&FINE_SIN_LOOKUP_TABLE, (index + 1) % points, points);
+    from + (((to - from) * fraction) >> INTERPOLATION_BITS)
+}
+
+/// Table entry `index` of a sine over `points`.
+#[cfg(not(feature = "quarter_wave"))]
+fn sample(table: &[i32], index: usize, _points: usize) -> i32 {
+    table[index]
+}
+
+/// Table entry `index` of a sine over `points`, mirrored from the quarter
+/// which is stored.
+#[cfg(feature = "quarter_wave")]
+fn sample(table: &[i32], index: usize, points: usize) -> i32 {
+    let quarter = points / 4;
+    match index / quarter {
+        0 => table[index],
+        1 => table[2 * quarter - index],
+        2 => -table[index - 2 * quarter],
+        _ => -table[4 * quarter - index],
+    }
+}
+
 
 //
 // Tests
@@ -37,4 +79,37 @@ mod tests {
         assert_eq!(((1.25*PI).sin() * current as f32) as i32, get_sine(180 + 45, current));
         assert_eq!(0, get_sine(360, current));
     }
+
+    #[test]
+    fn full_period() {
+        // Mirrored or not, the table follows the sine everywhere.
+        use std::f64::consts::PI;
+        let current = 10_000;
+        for degree in 0..360 {
+            let expected = (degree as f64 / 180.0 * PI).sin() * current as f64;
+            assert!((expected - get_sine(degree, current) as f64).abs() < 2.0);
+        }
+    }
+
+    #[test]
+    fn fine_sine_points() {
+        let current = 1000;
+        assert_eq!(0, sin(ElectricalAngle::ZERO, current));
+        assert_eq!(current, sin(ElectricalAngle::QUARTER, current));
+        assert_eq!(-current, sin(ElectricalAngle::from_degrees(270), current));
+        assert_eq!((current, 0), sin_cos(ElectricalAngle::QUARTER, current));
+        assert_eq!((0, current), sin_cos(ElectricalAngle::ZERO, current));
+    }
+
+    #[test]
+    fn fine_sine_interpolation() {
+        use std::f64::consts::PI;
+        let current = 100_000;
+        // Every 1/256 microstep, and in between the table entries.
+        for angle in (0..=u16::MAX).step_by(61) {
+            let expected = (angle as f64 / 65536.0 * 2.0 * PI).sin() * current as f64;
+            let value = sin(ElectricalAngle(angle), current);
+            assert!((expected - value as f64).abs() < 4.0, "{} {} {}", angle, expected, value);
+        }
+    }
 }
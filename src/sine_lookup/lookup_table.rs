@@ -1,19 +0,0 @@
-pub static SIN_LOOKUP_TABLE: [i32; 360] = [
-0,1143,2287,3429,4571,5711,6850,7986,9120,10251,11380,12504,13625,14742,15854,16961,18063,19160,20251,21336,22414,
-23485,24549,25606,26655,27696,28728,29752,30766,31772,32767,33753,34728,35692,36646,37589,38520,39439,40347,41242,42125,
-42994,43851,44694,45524,46340,47141,47929,48702,49459,50202,50930,51642,52338,53018,53683,54330,54962,55576,56174,56754,
-57318,57863,58392,58902,59394,59869,60325,60762,61182,61582,61964,62327,62671,62996,63301,63588,63855,64102,64330,64539,
-64728,64897,65046,65175,65285,65375,65445,65495,65525,65535,65525,65495,65445,65375,65285,65175,65046,64897,64728,64539,
-64330,64102,63855,63588,63301,62996,62671,62327,61964,61582,61182,60762,60325,59869,59394,58902,58392,57863,57318,56754,
-56174,55576,54962,54330,53683,53018,52338,51642,50930,50202,49459,48701,47929,47141,46340,45524,44694,43851,42994,42125,
-41242,40347,39439,38520,37589,36646,35692,34728,33753,32767,31771,30766,29752,28728,27696,26655,25606,24549,23485,22414,
-21336,20251,19160,18063,16961,15854,14742,13625,12504,11380,10251,9120,7986,6850,5711,4571,3429,2287,1143,0,
--1143,-2287,-3429,-4571,-5711,-6850,-7986,-9120,-10251,-11380,-12504,-13625,-14742,-15854,-16961,-18063,-19160,-20251,-21336,-22414,
--23485,-24549,-25606,-26655,-27696,-28728,-29752,-30766,-31772,-32767,-33753,-34728,-35692,-36646,-37589,-38520,-39439,-40347,-41242,-42125,
--42994,-43851,-44694,-45524,-46340,-47141,-47929,-48702,-49459,-50202,-50930,-51642,-52338,-53018,-53683,-54330,-54962,-55576,-56174,-56754,
--57318,-57863,-58392,-58902,-59394,-59869,-60325,-60763,-61182,-61582,-61964,-62327,-62671,-62996,-63301,-63588,-63855,-64102,-64330,-64539,
--64728,-64897,-65046,-65175,-65285,-65375,-65445,-65495,-65525,-65535,-65525,-65495,-65445,-65375,-65285,-65175,-65046,-64897,-64728,-64539,
--64330,-64102,-63855,-63588,-63301,-62996,-62671,-62327,-61964,-61582,-61182,-60762,-60325,-59869,-59394,-58902,-58392,-57863,-57318,-56754,
--56174,-55576,-54962,-54330,-53683,-53018,-52338,-51642,-50930,-50202,-49459,-48701,-47929,-47141,-46340,-45524,-44694,-43851,-42994,-42125,
--41242,-40347,-39439,-38520,-37589,-36646,-35692,-34728,-33753,-32767,-31771,-30766,-29752,-28728,-27696,-26655,-25606,-24549,-23485,-22414,
--21336,-20251,-19160,-18063,-16961,-15854,-14742,-13625,-12504,-11380,-10251,-9120,-7986,-6850,-5711,-4571,-3429,-2287,-1143];
\ No newline at end of file
@@ -1,5 +1,8 @@
+pub mod angle;
+mod config;
 pub mod lookup;
-mod lookup_table;
+mod lookup_table {
+    include!(concat!(env!("OUT_DIR"), "/lookup_table.rs"));
+}
 
-pub const SAMPLE_POINTS: u32 = 360;
-pub const SCALING_FACTOR: u32 = 65535; // 16bits
\ No newline at end of file
+pub use self::config::{FINE_SAMPLE_POINTS, SAMPLE_POINTS, SCALING_FACTOR};
\ No newline at end of file
@@ -0,0 +1,206 @@
+use crate::multi_turn::MultiTurnPosition;
+use crate::switches::Side;
+use crate::util;
+
+/// What to do with a target outside of the limits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitMode {
+    /// Move up to the limit.
+    Clamp,
+    /// Do not move at all.
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitViolation {
+    pub side: Side,
+    pub target: MultiTurnPosition,
+    /// Moving to the limit instead, otherwise the target was rejected.
+    pub clamped: bool,
+}
+
+/// Software travel limits, by default there are none.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoftLimits {
+    pub min: MultiTurnPosition,
+    pub max: MultiTurnPosition,
+    pub mode: LimitMode,
+    /// Towards a limit the velocity is kept low enough to stop at it with
+    /// this deceleration, in pulses per second squared.
+    pub deceleration: i64,
+    /// Lowest velocity close to a limit, so it is reached. Pulses per second.
+    pub creep_velocity: i32,
+}
+
+impl Default for SoftLimits {
+    fn default() -> Self {
+        Self {
+            min: MultiTurnPosition::MIN,
+            max: MultiTurnPosition::MAX,
+            mode: LimitMode::Reject,
+            deceleration: i64::MAX,
+            creep_velocity: 50,
+        }
+    }
+}
+
+impl SoftLimits {
+    pub fn new(min: MultiTurnPosition, max: MultiTurnPosition, mode: LimitMode) -> Self {
+        Self {
+            min,
+            max,
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Side of the limit the position is beyond, if any.
+    pub fn exceeded(&self, position: MultiTurnPosition) -> Option<Side> {
+        if position < self.min {
+            Some(Side::Negative)
+        } else if position > self.max {
+            Some(Side::Positive)
+        } else {
+            None
+        }
+    }
+
+    /// The target to move to, or the violation when it was rejected.
+    pub fn check_target(
+        &self,
+        target: MultiTurnPosition,
+    ) -> Result<MultiTurnPosition, LimitViolation> {
+        let side = match self.exceeded(target) {
+            Some(side) => side,
+            None => return Ok(target),
+        };
+        Err(LimitViolation {
+            side,
+            target,
+            clamped: self.mode == LimitMode::Clamp,
+        })
+    }
+
+    /// The limit at `side`.
+    pub fn limit(&self, side: Side) -> MultiTurnPosition {
+        match side {
+            Side::Negative => self.min,
+            Side::Positive => self.max,
+        }
+    }
+
+    /// Limit a velocity towards a limit to the braking envelope
+    /// `sqrt(2 * deceleration * distance)`, at least the creep velocity up to
+    /// the limit. Moving away from a limit is not restricted.
+    pub fn limit_velocity(&self, position: MultiTurnPosition, velocity: i32) -> i32 {
+        if velocity == 0 {
+            return 0;
+        }
+        let side = Side::from_sign(velocity);
+        let distance = self
+            .limit(side)
+            .saturating_distance(position)
+            .saturating_mul(side.sign() as i64);
+        if distance <= 0 {
+            return 0;
+        }
+        let envelope = util::sqrt(
+            (self.deceleration.max(0) as u64)
+                .saturating_mul(2)
+                .saturating_mul(distance as u64),
+        );
+        let max_velocity = envelope.max(self.creep_velocity.max(1) as u64) as i64;
+        (velocity as i64).clamp(-max_velocity, max_velocity) as i32
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(mode: LimitMode) -> SoftLimits {
+        SoftLimits {
+            deceleration: 200,
+            creep_velocity: 10,
+            ..SoftLimits::new((-1000).into(), 1000.into(), mode)
+        }
+    }
+
+    #[test]
+    fn targets() {
+        let limits = limits(LimitMode::Reject);
+        assert_eq!(Ok(500.into()), limits.check_target(500.into()));
+        assert_eq!(
+            Err(LimitViolation {
+                side: Side::Positive,
+                target: 1001.into(),
+                clamped: false
+            }),
+            limits.check_target(1001.into())
+        );
+
+        let limits = self::limits(LimitMode::Clamp);
+        let violation = limits.check_target((-1001).into()).unwrap_err();
+        assert_eq!(Side::Negative, violation.side);
+        assert!(violation.clamped);
+        assert_eq!(limits.min, limits.limit(violation.side));
+    }
+
+    #[test]
+    fn braking_envelope() {
+        let limits = limits(LimitMode::Reject);
+        assert_eq!(200, limits.limit_velocity(0.into(), 200));
+        // sqrt(2 * 200 * 50)
+        assert_eq!(141, limits.limit_velocity(950.into(), 200));
+        assert_eq!(20, limits.limit_velocity(999.into(), 200));
+        // Creeping up to the limit.
+        let creeping = SoftLimits {
+            deceleration: 0,
+            ..limits
+        };
+        assert_eq!(10, creeping.limit_velocity(999.into(), 200));
+        assert_eq!(0, limits.limit_velocity(1000.into(), 200));
+        assert_eq!(0, limits.limit_velocity(1100.into(), 200));
+
+        // Moving away is not limited.
+        assert_eq!(-200, limits.limit_velocity(1000.into(), -200));
+        assert_eq!(-141, limits.limit_velocity((-950).into(), -200));
+    }
+
+    #[test]
+    fn jog_reaches_limit() {
+        let limits = limits(LimitMode::Reject);
+        // Integrated at 1 kHz, as `PositionControl` does.
+        let mut position = MultiTurnPosition::ZERO;
+        let mut remainder = 0;
+        for _ in 0..100_000 {
+            remainder += limits.limit_velocity(position, 2000);
+            let pulses = remainder / 1000;
+            remainder -= pulses * 1000;
+            position = position.saturating_add(pulses as i64);
+            assert!(position <= limits.max);
+        }
+        assert_eq!(limits.max, position);
+    }
+
+    #[test]
+    fn no_limits_by_default() {
+        let limits = SoftLimits::default();
+        assert_eq!(
+            Ok(MultiTurnPosition::MAX),
+            limits.check_target(MultiTurnPosition::MAX)
+        );
+        assert_eq!(
+            i32::MAX,
+            limits.limit_velocity(MultiTurnPosition::ZERO, i32::MAX)
+        );
+        assert_eq!(
+            i32::MIN,
+            limits.limit_velocity(MultiTurnPosition::ZERO, i32::MIN)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StallConfig {
+    /// Electrical degrees the rotor may lag the commanded angle.
+    pub max_lag: i32,
+    /// Re-sync the commanded angle to the rotor on a stall and try again.
+    pub resync: bool,
+    /// Fault after this many stalls in a row.
+    pub max_stalls: u32,
+    /// Updates without a stall before the stalls in a row are forgotten.
+    pub clear_updates: u32,
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            max_lag: 135,
+            resync: true,
+            max_stalls: 3,
+            clear_updates: 2000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StallEvent {
+    Stall,
+    /// Too many stalls in a row, latched until `reset_fault`.
+    Fault,
+}
+
+/// Detects a rotor lagging the commanded angle, counts the stalls.
+pub struct StallDetector {
+    config: StallConfig,
+    stalled: bool,
+    stall_count: u32,
+    stalls_in_row: u32,
+    clear_count: u32,
+    fault: bool,
+}
+
+impl StallDetector {
+    pub fn new(config: StallConfig) -> Self {
+        Self {
+            config,
+            stalled: false,
+            stall_count: 0,
+            stalls_in_row: 0,
+            clear_count: 0,
+            fault: false,
+        }
+    }
+    pub fn config(&self) -> &StallConfig {
+        &self.config
+    }
+    pub fn set_config(&mut self, config: StallConfig) {
+        self.config = config;
+    }
+    /// All stalls since the start.
+    pub fn stall_count(&self) -> u32 {
+        self.stall_count
+    }
+    pub fn is_fault(&self) -> bool {
+        self.fault
+    }
+    pub fn reset_fault(&mut self) {
+        self.fault = false;
+        self.stalls_in_row = 0;
+        self.clear_count = 0;
+    }
+
+    /// `lag` in electrical degrees, `None` when it can not be measured.
+    pub fn update(&mut self, lag: Option<i32>) -> Option<StallEvent> {
+        let stalled = match lag {
+            Some(lag) => lag.saturating_abs() > self.config.max_lag,
+            None => false,
+        };
+
+        if !stalled {
+            self.stalled = false;
+            if self.stalls_in_row > 0 {
+                self.clear_count = self.clear_count.saturating_add(1);
+                if self.clear_count >= self.config.clear_updates {
+                    self.stalls_in_row = 0;
+                }
+            }
+            return None;
+        }
+        if self.stalled {
+            // Same stall.
+            return None;
+        }
+
+        self.stalled = true;
+        self.clear_count = 0;
+        self.stall_count = self.stall_count.saturating_add(1);
+        self.stalls_in_row = self.stalls_in_row.saturating_add(1);
+        if self.stalls_in_row >= self.config.max_stalls {
+            self.fault = true;
+            Some(StallEvent::Fault)
+        } else {
+            Some(StallEvent::Stall)
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> StallDetector {
+        StallDetector::new(StallConfig {
+            max_lag: 90,
+            max_stalls: 3,
+            clear_updates: 2,
+            ..StallConfig::default()
+        })
+    }
+
+    #[test]
+    fn counts_stalls() {
+        let mut detector = detector();
+        assert_eq!(None, detector.update(Some(90)));
+        assert_eq!(Some(StallEvent::Stall), detector.update(Some(-91)));
+        assert_eq!(None, detector.update(Some(120)));
+        assert_eq!(None, detector.update(None));
+        assert_eq!(Some(StallEvent::Stall), detector.update(Some(100)));
+        assert_eq!(2, detector.stall_count());
+        assert!(!detector.is_fault());
+    }
+
+    #[test]
+    fn fault_after_stalls_in_row() {
+        let mut detector = detector();
+        for _ in 0..2 {
+            assert_eq!(Some(StallEvent::Stall), detector.update(Some(100)));
+            detector.update(Some(0));
+        }
+        assert_eq!(Some(StallEvent::Fault), detector.update(Some(100)));
+        assert!(detector.is_fault());
+
+        detector.reset_fault();
+        assert!(!detector.is_fault());
+        detector.update(Some(0));
+        assert_eq!(Some(StallEvent::Stall), detector.update(Some(100)));
+    }
+
+    #[test]
+    fn stalls_in_row_are_forgotten() {
+        let mut detector = detector();
+        for _ in 0..5 {
+            assert_eq!(Some(StallEvent::Stall), detector.update(Some(100)));
+            detector.update(Some(0));
+            detector.update(Some(0));
+        }
+        assert_eq!(5, detector.stall_count());
+    }
+}
@@ -0,0 +1,144 @@
+/// How to stop on an emergency stop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopCategory {
+    /// Disable the coils right away.
+    Immediate,
+    /// Decelerate to standstill, then disable the coils.
+    Controlled,
+}
+
+/// What to do after a quick stop reached standstill.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AfterStop {
+    Disable,
+    /// Keep the position with a percentage of the configured current.
+    Hold {
+        current_percent: i32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StopConfig {
+    /// Pulses per second squared.
+    pub deceleration: i32,
+    /// Electrical cycles per second squared, for a stop from the open loop
+    /// `rotate`.
+    pub rotate_deceleration: i32,
+    /// Keep the position at full current for this long after standstill.
+    pub hold_time_ms: u32,
+    /// Only for a quick stop, a disable or emergency stop always disables.
+    pub after_stop: AfterStop,
+    /// Decelerate before a disable, instead of letting the motor run out.
+    pub controlled_disable: bool,
+}
+
+impl Default for StopConfig {
+    fn default() -> Self {
+        Self {
+            deceleration: 48_000,
+            rotate_deceleration: 1000,
+            hold_time_ms: 100,
+            after_stop: AfterStop::Disable,
+            controlled_disable: true,
+        }
+    }
+}
+
+/// Ramps a velocity down to zero with a constant deceleration.
+pub struct VelocityRamp {
+    velocity: i32,
+    deceleration: i32,
+    update_frequency: i32,
+    remainder: i32,
+}
+
+impl VelocityRamp {
+    /// `update_frequency` is the number of `update` calls per second.
+    pub fn new(deceleration: i32, update_frequency: i32) -> Self {
+        Self {
+            velocity: 0,
+            deceleration,
+            update_frequency,
+            remainder: 0,
+        }
+    }
+    pub fn set_deceleration(&mut self, deceleration: i32) {
+        self.deceleration = deceleration;
+    }
+    pub fn start(&mut self, velocity: i32) {
+        self.velocity = velocity;
+        self.remainder = 0;
+    }
+    pub fn velocity(&self) -> i32 {
+        self.velocity
+    }
+    pub fn is_stopped(&self) -> bool {
+        self.velocity == 0
+    }
+
+    /// Next velocity on the ramp.
+    pub fn update(&mut self) -> i32 {
+        self.update_by(1)
+    }
+    /// Velocity on the ramp after `updates` update periods.
+    pub fn update_by(&mut self, updates: u32) -> i32 {
+        let update_frequency = self.update_frequency.max(1) as i64;
+        let remainder =
+            self.remainder as i64 + self.deceleration.unsigned_abs() as i64 * updates as i64;
+        let step = remainder / update_frequency;
+        self.remainder = (remainder - step * update_frequency) as i32;
+        let step = step.min(i32::MAX as i64) as i32;
+
+        self.velocity = if self.velocity > 0 {
+            (self.velocity - step).max(0)
+        } else {
+            (self.velocity + step).min(0)
+        };
+        self.velocity
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramp_down() {
+        let mut ramp = VelocityRamp::new(1000, 100);
+        ramp.start(100);
+        assert_eq!(90, ramp.update());
+        for _ in 0..8 {
+            ramp.update();
+        }
+        assert_eq!(10, ramp.velocity());
+        assert_eq!(0, ramp.update());
+        assert!(ramp.is_stopped());
+        assert_eq!(0, ramp.update());
+    }
+
+    #[test]
+    fn ramp_down_negative_and_slow() {
+        // Less than one pulse per second, per update.
+        let mut ramp = VelocityRamp::new(50, 100);
+        ramp.start(-2);
+        assert_eq!(-2, ramp.update());
+        assert_eq!(-1, ramp.update());
+        assert_eq!(-1, ramp.update());
+        assert_eq!(0, ramp.update());
+    }
+
+    #[test]
+    fn ramp_down_by_elapsed_time() {
+        // Counted in CPU cycles, for the varying open loop update period.
+        let mut ramp = VelocityRamp::new(1000, 72_000_000);
+        ramp.start(100);
+        assert_eq!(99, ramp.update_by(72_000));
+        assert_eq!(99, ramp.update_by(36_000));
+        assert_eq!(98, ramp.update_by(36_000));
+        assert_eq!(0, ramp.update_by(u32::MAX));
+    }
+}
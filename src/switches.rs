@@ -0,0 +1,48 @@
+/// End of the travel, in the direction of the position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Negative,
+    Positive,
+}
+
+impl Side {
+    /// Positive side for zero and up.
+    pub fn from_sign(value: i32) -> Self {
+        if value < 0 {
+            Side::Negative
+        } else {
+            Side::Positive
+        }
+    }
+    pub fn sign(self) -> i32 {
+        match self {
+            Side::Negative => -1,
+            Side::Positive => 1,
+        }
+    }
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Negative => Side::Positive,
+            Side::Positive => Side::Negative,
+        }
+    }
+}
+
+/// The switch inputs, polled by `MotorControl::update`.
+pub trait SwitchInput {
+    /// The limit (end-stop) switch at `side` is active.
+    fn limit_active(&self, side: Side) -> bool;
+    /// The emergency stop line is active.
+    fn estop_active(&self) -> bool {
+        false
+    }
+}
+
+/// For a setup without switches.
+pub struct NoSwitches;
+
+impl SwitchInput for NoSwitches {
+    fn limit_active(&self, _side: Side) -> bool {
+        false
+    }
+}
@@ -12,3 +12,23 @@ where
         value
     }
 }
+
+/// Integer square root, rounded down.
+pub fn sqrt(value: u64) -> u64 {
+    let mut value = value;
+    let mut result = 0;
+    let mut bit = 1 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if value >= result + bit {
+            value -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
@@ -0,0 +1,138 @@
+use crate::calibration::ANGLE_FRACTION_BITS;
+use crate::sine_lookup::angle::ElectricalAngle;
+use crate::sine_lookup::{lookup, SCALING_FACTOR};
+
+/// Points of a waveform table over one electrical cycle.
+pub const WAVEFORM_POINTS: usize = 256;
+const INTERPOLATION_BITS: u32 = 16 - WAVEFORM_POINTS.trailing_zeros();
+
+/// Coil current over the electrical angle, `SCALING_FACTOR` is the full
+/// current. Coil B uses the same waveform, 90 degrees further.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaveformTable {
+    points: [i32; WAVEFORM_POINTS],
+}
+
+impl WaveformTable {
+    /// A pure sine, to be changed point by point.
+    pub fn sine() -> Self {
+        let mut points = [0; WAVEFORM_POINTS];
+        for (index, point) in points.iter_mut().enumerate() {
+            *point = lookup::sin(Self::angle_at(index), SCALING_FACTOR as i32);
+        }
+        Self { points }
+    }
+
+    /// A sine with the angle corrected by a fourth harmonic, in fixed point
+    /// degrees. See `Calibration::harmonic_error`.
+    pub fn harmonic_corrected(sine: i32, cosine: i32) -> Self {
+        let mut points = [0; WAVEFORM_POINTS];
+        for (index, point) in points.iter_mut().enumerate() {
+            let angle = Self::angle_at(index);
+            let harmonic = ElectricalAngle(angle.0.wrapping_mul(4));
+            let (sin, cos) = lookup::sin_cos(harmonic, 1 << 16);
+            let error = (sine as i64 * sin as i64 + cosine as i64 * cos as i64) >> 16;
+            let error = ElectricalAngle::from_fixed_point(error as i32, ANGLE_FRACTION_BITS);
+            *point = lookup::sin(angle.wrapping_add(error), SCALING_FACTOR as i32);
+        }
+        Self { points }
+    }
+
+    pub fn points(&self) -> &[i32; WAVEFORM_POINTS] {
+        &self.points
+    }
+
+    /// Values outside of the full current are limited.
+    pub fn set_point(&mut self, index: usize, value: i32) {
+        if let Some(point) = self.points.get_mut(index) {
+            let max = SCALING_FACTOR as i32;
+            *point = value.clamp(-max, max);
+        }
+    }
+
+    /// `current` at `angle`, interpolated between the points.
+    pub fn value(&self, angle: ElectricalAngle, current: i32) -> i32 {
+        let index = (angle.0 >> INTERPOLATION_BITS) as usize;
+        let fraction = (angle.0 & ((1 << INTERPOLATION_BITS) - 1)) as i64;
+        let from = self.points[index] as i64;
+        let to = self.points[(index + 1) % WAVEFORM_POINTS] as i64;
+        let point = from + (((to - from) * fraction) >> INTERPOLATION_BITS);
+        (current as i64 * point / SCALING_FACTOR as i64) as i32
+    }
+
+    fn angle_at(index: usize) -> ElectricalAngle {
+        ElectricalAngle((index << INTERPOLATION_BITS) as u16)
+    }
+}
+
+/// The coil current waveform, selectable at runtime.
+// No allocator to box the table.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Table(WaveformTable),
+}
+
+impl Waveform {
+    /// Coil current for `current` at `angle`.
+    pub fn value(&self, angle: ElectricalAngle, current: i32) -> i32 {
+        match self {
+            Waveform::Sine => lookup::sin(angle, current),
+            Waveform::Table(table) => table.value(angle, current),
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::ANGLE_SCALE;
+
+    #[test]
+    fn sine_table() {
+        let table = Waveform::Table(WaveformTable::sine());
+        for angle in (0..=u16::MAX).step_by(97) {
+            let angle = ElectricalAngle(angle);
+            let difference = table.value(angle, 1000) - Waveform::Sine.value(angle, 1000);
+            assert!(difference.abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn custom_points() {
+        const FULL: i32 = SCALING_FACTOR as i32;
+        let mut table = WaveformTable::sine();
+        table.set_point(64, FULL / 2);
+        table.set_point(65, i32::MAX);
+        table.set_point(WAVEFORM_POINTS, 0);
+        assert_eq!(FULL / 2, table.value(ElectricalAngle::QUARTER, FULL));
+        assert_eq!(FULL, table.value(ElectricalAngle(65 << 8), FULL));
+        assert_eq!(
+            (FULL / 2 + FULL) / 2,
+            table.value(ElectricalAngle((64 << 8) + 128), FULL)
+        );
+    }
+
+    #[test]
+    fn harmonic_correction() {
+        // Commands 3 degrees further at 22.5 degrees, nothing at the steps.
+        let table = WaveformTable::harmonic_corrected(3 * ANGLE_SCALE, 0);
+        let at = |angle: i32| {
+            let angle = ElectricalAngle::from_fixed_point(angle, ANGLE_FRACTION_BITS);
+            (table.value(angle, 10_000), lookup::sin(angle, 10_000))
+        };
+        let (value, sine) = at(0);
+        assert_eq!(sine, value);
+        assert_eq!((10_000, 10_000), at(90 * ANGLE_SCALE));
+
+        let (value, _) = at(45 * ANGLE_SCALE / 2);
+        let (_, expected) = at(51 * ANGLE_SCALE / 2);
+        assert!((expected - value).abs() <= 2, "{} {}", expected, value);
+    }
+}